@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use compact_str::CompactString;
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+
+use crate::config::{Lockout, ServerConfig, Token};
+
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_BAN: Duration = Duration::from_secs(5 * 60);
+
+/// Who a client is authenticated as.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Identity {
+    /// Holds the server `token`.
+    Token,
+    /// Holds the token of one of the server `credentials`.
+    Credential(CompactString),
+}
+
+/// Look up the identity whose token signed `msg` with `proof`, every configured token is checked
+/// in constant time.
+pub fn authenticate(config: &ServerConfig, msg: &[u8], proof: &[u8]) -> Option<Identity> {
+    let verify = |t: &Option<Token>| t.as_ref().map_or(false, |t| t.verify(msg, proof));
+
+    let mut identity = verify(&config.token).then(|| Identity::Token);
+    for (name, c) in &config.credentials {
+        if verify(&c.token) && identity.is_none() {
+            identity = Some(Identity::Credential(name.clone()));
+        }
+    }
+    identity
+}
+
+/// Map a client certificate, already verified against the `client_auth` CA, to the credential
+/// named after the common name of its subject.
+pub fn authenticate_cert(config: &ServerConfig, certs: &[rustls::Certificate]) -> Option<Identity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    config
+        .credentials
+        .get_key_value(name)
+        .map(|(name, _)| Identity::Credential(name.clone()))
+}
+
+impl Identity {
+    /// Whether the identity may register `route`, according to the current `config`.
+    pub fn may_register(&self, config: &ServerConfig, route: &str) -> bool {
+        match self {
+            Identity::Token => config.token.is_some(),
+            Identity::Credential(name) => config
+                .credentials
+                .get(name)
+                .map_or(false, |c| c.routes.contains(route)),
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::Token => f.write_str("token"),
+            Identity::Credential(name) => write!(f, "`{}`", name),
+        }
+    }
+}
+
+/// Authentication failures by source address.
+#[derive(Default)]
+pub struct Lockouts(Mutex<FnvHashMap<IpAddr, Failures>>);
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Lockouts {
+    /// Whether `ip` is banned, lifting its ban once expired.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut lockouts = self.0.lock();
+        match lockouts.get(&ip).and_then(|f| f.banned_until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                lockouts.remove(&ip);
+                log::info!("ban of {} lifted", ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failed authentication of `ip`, banning it when it fails too often.
+    pub fn fail(&self, ip: IpAddr, config: &Lockout) {
+        let max_failures = config.max_failures.unwrap_or(DEFAULT_MAX_FAILURES);
+        let ban = config.ban.map_or(DEFAULT_BAN, |t| *t.duration());
+        let now = Instant::now();
+
+        let mut lockouts = self.0.lock();
+        // forget the addresses that stopped failing
+        lockouts.retain(|_, f| {
+            f.banned_until
+                .map_or(now - f.since < ban, |until| until > now)
+        });
+
+        let failures = lockouts.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+            banned_until: None,
+        });
+        failures.count += 1;
+        if failures.count >= max_failures && failures.banned_until.is_none() {
+            failures.banned_until = Some(now + ban);
+            log::warn!(
+                "{} banned for {:?} after {} failed authentications",
+                ip,
+                ban,
+                failures.count
+            );
+        }
+    }
+
+    /// Forget the failures of `ip` after it authenticated.
+    pub fn succeed(&self, ip: IpAddr) {
+        self.0.lock().remove(&ip);
+    }
+}
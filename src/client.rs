@@ -1,63 +1,163 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use bytes::{Buf, BytesMut};
-use integer_encoding::VarIntAsyncReader;
-use quinn::Endpoint;
-use rustls::RootCertStore;
+use bytes::{Buf, Bytes, BytesMut};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use quinn::{Connection, Endpoint, IncomingBiStreams, NewConnection, RecvStream, SendStream};
 use tokio::io::AsyncReadExt;
 use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-use crate::config::{ClientConfig, RouteType};
+use crate::config::{split_host_port, ClientConfig, ConnectStrategy, RouteType, Token};
 use crate::proto::{
-    read_proto, write_proto, Auth, RegisterRoute, RegisterRouteError, RegisterRouteRes,
-    StreamStart, VarIntWriter, CODE_AUTH_SUCCESS,
+    read_proto, spawn_control_reader, spawn_control_writer, write_proto, Auth, Challenge,
+    CloseReason, Closed, Control, ControlSender, Hello, Login, RegisterRoute, RegisterRouteRes,
+    Stats, StreamStart, UdpFlow, UdpStream, UnregisterRouteRes, CODE_AUTH_SUCCESS,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
-use crate::quic::{transport_config, QuicStream};
+use crate::quic::{client_crypto, transport_config, QuicStream};
+use crate::udp::{
+    self, read_mux_frame, read_packet, read_udp_stream, write_mux_packet, Activity, FlowGuard,
+    FlowSender, Flows, MuxFrame, SizeLimit,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_ROUTE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MIN_ROUTE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_RETRY_MULTIPLIER: f64 = 2.0;
+const DEFAULT_MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_RETRY_JITTER: f64 = 0.2;
+const DEFAULT_RETRY_RESET_AFTER: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_GROWN_BIDI_STREAMS: u32 = 1024;
+
+/// Exponential backoff between the reconnections of the client.
+#[derive(Default)]
+pub struct Backoff {
+    retries: usize,
+    delay: Option<Duration>,
+    connected_at: Option<Instant>,
+}
+
+impl Backoff {
+    /// The delay before the next attempt, none once `max_retry` is reached or if the client
+    /// doesn't reconnect.
+    pub fn next_delay(&mut self, config: &ClientConfig) -> Option<Duration> {
+        let reset_after = config
+            .retry_reset_after
+            .map_or(DEFAULT_RETRY_RESET_AFTER, |t| *t.duration());
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= reset_after {
+                self.retries = 0;
+                self.delay = None;
+            }
+        }
+
+        let initial = *config.retry_interval?.duration();
+        let max = config
+            .max_retry_interval
+            .map_or(DEFAULT_MAX_RETRY_INTERVAL, |t| *t.duration());
+        let multiplier = config.retry_multiplier.unwrap_or(DEFAULT_RETRY_MULTIPLIER);
+        let jitter = config.retry_jitter.unwrap_or(DEFAULT_RETRY_JITTER);
+
+        self.retries += 1;
+        if config.max_retry.map_or(false, |max| self.retries > max) {
+            return None;
+        }
+        let max = max.max(initial);
+        let delay = self.delay.map_or(initial, |d| scale(d, multiplier, max));
+        self.delay = Some(delay);
+
+        // spread the reconnections of many clients after a server restart
+        let random = ring::rand::generate::<[u8; 4]>(&ring::rand::SystemRandom::new())
+            .map_or(0.5, |r| {
+                u32::from_le_bytes(r.expose()) as f64 / u32::MAX as f64
+            });
+        let spread = 1.0 + jitter * (random * 2.0 - 1.0);
+        Some(scale(delay, spread, delay.saturating_mul(2)))
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+}
+
+/// `d * factor` clamped to `0..=max`, without the panics of `Duration::mul_f64`.
+fn scale(d: Duration, factor: f64, max: Duration) -> Duration {
+    let secs = d.as_secs_f64() * factor;
+    if secs.is_nan() || secs <= 0.0 {
+        Duration::ZERO
+    } else if secs >= max.as_secs_f64() {
+        max
+    } else {
+        Duration::from_secs_f64(secs)
+    }
+}
+
+/// Whether reconnecting can't help until the configuration is changed.
+pub fn is_fatal(err: &anyhow::Error) -> bool {
+    if let Some(closed) = Closed::from_error(err) {
+        return closed.reason.is_fatal();
+    }
+    err.chain().any(|e| {
+        e.is::<InvalidConfig>() || e.is::<quinn::ConnectError>() || e.is::<rustls::Error>()
+    })
+}
 
-pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Result<()> {
-    let mut roots = RootCertStore::empty();
-    config.cert.iter().try_for_each(|c| roots.add(c))?;
+/// The TLS settings of the configuration can't be used.
+#[derive(Debug)]
+struct InvalidConfig(String);
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// `reload` is watched for configuration reloads while connected, `session` is the session of
+/// the previous connection that is resumed and then replaced with the current one.
+pub async fn run(
+    mut reload: watch::Receiver<Arc<ClientConfig>>,
+    backoff: &mut Backoff,
+    session: &mut Option<u64>,
+) -> anyhow::Result<()> {
+    let config = Arc::clone(&reload.borrow());
+
+    let client_config = {
+        let crypto = client_crypto(&config).map_err(|err| InvalidConfig(format!("{:#}", err)))?;
+        let mut c = quinn::ClientConfig::new(Arc::new(crypto));
+        c.transport = Arc::new(transport_config());
+        Arc::get_mut(&mut c.transport)
+            .unwrap()
+            .max_concurrent_bidi_streams(config.max_concurrent_bidi_streams.unwrap_or(100).into());
+        c
+    };
 
     let ep = Endpoint::client("[::]:0".parse().unwrap())?;
-    let mut new_conn = ep
-        .connect_with(
-            {
-                let mut c = quinn::ClientConfig::with_root_certificates(roots);
-                c.transport = Arc::new(transport_config());
-                Arc::get_mut(&mut c.transport)
-                    .unwrap()
-                    .max_concurrent_bidi_streams(
-                        config.max_concurrent_bidi_streams.unwrap_or(100).into(),
-                    );
-                c
-            },
-            tokio::net::lookup_host(&*config.remote)
-                .await?
-                .next()
-                .ok_or_else(|| anyhow::anyhow!("couldn't resolve to an address"))?,
-            config.remote.split_once(':').unwrap().0,
-        )?
-        .await?;
-    log::info!("connecting {}", &config.remote);
-    *retry_num = 0;
-
-    let mut handshake_stream = new_conn.connection.open_bi().await?;
-
-    // authorization
-    write_proto::<_, 32>(
-        &mut handshake_stream.0,
-        Auth {
-            token: config.token.as_str().to_owned(),
-        },
-    )
-    .await?;
-
-    assert_eq!(
-        handshake_stream.1.read_u8().await?,
-        CODE_AUTH_SUCCESS,
-        "authentication failed"
-    );
+    let (new_conn, remote, server_name) = connect(&ep, &client_config, &config).await?;
+    log::info!("connecting {} ({})", server_name, remote);
+    backoff.connected();
+
+    let (mut handshake_stream, features) =
+        authorize(&new_conn.connection, &config, None, *session).await?;
+    let id = read_proto::<u64, 8>(&mut handshake_stream.1).await?;
+    if session.replace(id) == Some(id) {
+        log::info!("session {:x} resumed", id);
+    } else {
+        log::debug!("session {:x} started", id);
+    }
 
     let register_route = config
         .route
@@ -70,40 +170,412 @@ pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Re
 
     write_proto::<Vec<RegisterRoute>, 1024>(&mut handshake_stream.0, register_route).await?;
 
-    let res = read_proto::<RegisterRouteRes, 32>(&mut handshake_stream.1).await?;
+    let res = read_proto::<Vec<(String, RegisterRouteRes)>, 256>(&mut handshake_stream.1).await?;
 
-    match res {
-        RegisterRouteRes::Ok => {}
-        RegisterRouteRes::Err(RegisterRouteError::Repeated(route)) => {
-            anyhow::bail!("route `{}` has been registered on the server", route.name)
+    // failed routes are registered again at every retry interval
+    let mut failed = FnvHashSet::default();
+    handle_register_res(res, &mut failed);
+
+    let mut conns = vec![(new_conn, features)];
+    for _ in 1..config.pool_size.unwrap_or(1) {
+        let new_conn = ep
+            .connect_with(client_config.clone(), remote, server_name)?
+            .await?;
+        let (mut handshake_stream, features) =
+            authorize(&new_conn.connection, &config, Some(id), None).await?;
+        handshake_stream.0.finish().await?;
+        conns.push((new_conn, features));
+    }
+
+    log::info!("handshake finish");
+
+    let connections = conns
+        .iter()
+        .map(|(c, _)| c.connection.clone())
+        .collect::<Vec<_>>();
+    let active_streams = Arc::new(AtomicU64::new(0));
+
+    let accept_loop = futures_util::future::try_join_all(conns.into_iter().map(|(c, features)| {
+        accept_streams(
+            c.connection,
+            c.bi_streams,
+            udp::spawn_flows(features, c.datagrams),
+            reload.clone(),
+            Arc::clone(&active_streams),
+        )
+    }));
+
+    // the handshake stream becomes the control stream
+    let (send_stream, recv_stream) = handshake_stream;
+    let control = spawn_control_writer(send_stream);
+    let mut messages = spawn_control_reader(recv_stream);
+
+    let control_loop = async {
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        let start = Instant::now();
+        let mut current = Arc::clone(&config);
+        // a zero `retry_interval` only makes the reconnections immediate
+        let retry_interval = config
+            .retry_interval
+            .map_or(DEFAULT_ROUTE_RETRY_INTERVAL, |t| *t.duration())
+            .max(MIN_ROUTE_RETRY_INTERVAL);
+        let mut retry =
+            tokio::time::interval_at(tokio::time::Instant::now() + retry_interval, retry_interval);
+        let base_streams = config.max_concurrent_bidi_streams.unwrap_or(100);
+        let mut max_streams = base_streams;
+        let mut grown = false;
+        let max_grown_streams = config
+            .max_grown_bidi_streams
+            .unwrap_or(DEFAULT_MAX_GROWN_BIDI_STREAMS)
+            .max(max_streams);
+        loop {
+            tokio::select! {
+                Ok(()) = reload.changed() => {
+                    let new = Arc::clone(&reload.borrow());
+                    failed.retain(|name: &String| new.route.contains_key(name.as_str()));
+                    reload_routes(&current, &new, &control);
+                    current = new;
+                }
+                _ = retry.tick(), if !failed.is_empty() => {
+                    let register = failed
+                        .drain()
+                        .filter_map(|name| {
+                            let route = current.route.get(name.as_str())?;
+                            Some(RegisterRoute { name, _type: route._type })
+                        })
+                        .collect::<Vec<_>>();
+                    log::info!("retry registering {} routes", register.len());
+                    control.send(Control::RegisterRoute(register)).ok();
+                }
+                _ = ping.tick() => {
+                    control.send(Control::Ping(start.elapsed().as_micros() as u64)).ok();
+                    // the stats of the server are only logged
+                    if log::log_enabled!(log::Level::Debug) {
+                        control.send(Control::Stats).ok();
+                    }
+                    // a grown limit shrinks back by half its excess each interval the server
+                    // doesn't ask for more
+                    if max_streams > base_streams && !grown {
+                        max_streams -= (max_streams - base_streams + 1) / 2;
+                        log::debug!("stream limit shrunk to {}", max_streams);
+                        for connection in &connections {
+                            connection.set_max_concurrent_bi_streams(max_streams.into());
+                        }
+                    }
+                    grown = false;
+                }
+                msg = messages.recv() => match msg.context("control stream closed")?? {
+                    Control::Ping(n) => {
+                        control.send(Control::Pong(n)).ok();
+                    }
+                    Control::Pong(n) => {
+                        log::debug!(
+                            "pong from server, rtt: {:?}",
+                            start.elapsed().saturating_sub(Duration::from_micros(n))
+                        );
+                    }
+                    Control::Stats => {
+                        let routes = current.route.keys().map(|k| k.as_str().to_owned()).collect();
+                        control
+                            .send(Control::StatsRes(Stats {
+                                routes,
+                                active_streams: active_streams.load(Ordering::Relaxed),
+                            }))
+                            .ok();
+                    }
+                    Control::StatsRes(stats) => {
+                        log::debug!("server stats: {:?}", stats);
+                    }
+                    Control::RegisterRouteRes(res) => {
+                        handle_register_res(res, &mut failed);
+                        if !failed.is_empty() {
+                            retry.reset();
+                        }
+                    }
+                    Control::UnregisterRouteRes(res) => {
+                        for (name, res) in res {
+                            match res {
+                                UnregisterRouteRes::Ok => {
+                                    log::info!("route `{}` unregistered", name)
+                                }
+                                UnregisterRouteRes::NotRegistered => {
+                                    log::warn!("route `{}` was not registered", name)
+                                }
+                            }
+                        }
+                    }
+                    Control::RouteClosed(name) => {
+                        log::warn!("route `{}` closed by the server", name);
+                    }
+                    Control::GrowStreams(n) => {
+                        grown = true;
+                        let limit = max_streams.saturating_add(n).min(max_grown_streams);
+                        if limit > max_streams {
+                            max_streams = limit;
+                            log::debug!("stream limit grown to {} by the server", max_streams);
+                            for connection in &connections {
+                                connection.set_max_concurrent_bi_streams(max_streams.into());
+                            }
+                        } else {
+                            log::debug!("stream limit already at its maximum {}", max_streams);
+                        }
+                    }
+                    Control::Close(reason) => {
+                        log::info!("server closing: {}", reason);
+                        return anyhow::Result::<()>::Ok(());
+                    }
+                    msg => log::warn!("unexpected control message from server: {:?}", msg),
+                },
+            }
         }
-        RegisterRouteRes::Err(RegisterRouteError::RouteNotFound(route)) => {
-            anyhow::bail!("route: `{}` does not exist on the server", route.name)
+    };
+
+    tokio::select! {
+        res = control_loop => res?,
+        res = accept_loop => {
+            res?;
+        }
+    }
+    for connection in connections {
+        CloseReason::Shutdown.close(&connection, "client shutdown");
+    }
+
+    ep.wait_idle().await;
+    log::info!("client closed");
+
+    Ok(())
+}
+
+/// Connect to the first reachable remote, the additional connections of the session go to the
+/// same address.
+async fn connect<'a>(
+    ep: &Endpoint,
+    client_config: &quinn::ClientConfig,
+    config: &'a ClientConfig,
+) -> anyhow::Result<(NewConnection, SocketAddr, &'a str)> {
+    let mut last_err = None;
+    for remote in &config.remote {
+        let (host, port) = split_host_port(remote)?;
+        let server_name = config.server_name.as_deref().unwrap_or(host);
+        if server_name.parse::<IpAddr>().is_ok() {
+            log::warn!("certificates are verified by name, set `server_name` to connect by ip");
         }
-        RegisterRouteRes::Err(RegisterRouteError::Other(err, route)) => {
-            anyhow::bail!(
-                "error: `{}` occurred while registering route `{}`",
-                err,
-                route.name
-            )
+        let mut addrs = match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => addrs.collect::<Vec<_>>(),
+            Err(err) => {
+                log::warn!("couldn't resolve {}: {}", remote, err);
+                last_err = Some(err.into());
+                continue;
+            }
+        };
+
+        let delay = match config.connect_strategy {
+            ConnectStrategy::Ordered => None,
+            ConnectStrategy::HappyEyeballs => {
+                interleave_families(&mut addrs);
+                Some(HAPPY_EYEBALLS_DELAY)
+            }
+        };
+        match race(ep, client_config, addrs, server_name, delay).await {
+            Ok((new_conn, addr)) => return Ok((new_conn, addr, server_name)),
+            Err(err) => {
+                log::warn!("couldn't connect to {}: {:#}", remote, err);
+                last_err = Some(err);
+            }
         }
     }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("couldn't resolve to an address")))
+}
 
-    log::info!("handshake finish");
+/// Connect to one of `addrs`, starting the attempts in order. The next attempt starts when the
+/// previous one fails, or after `delay` if there is one.
+async fn race(
+    ep: &Endpoint,
+    client_config: &quinn::ClientConfig,
+    addrs: Vec<SocketAddr>,
+    server_name: &str,
+    delay: Option<Duration>,
+) -> anyhow::Result<(NewConnection, SocketAddr)> {
+    let mut pending = addrs.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    while let Some(addr) = pending.next() {
+        attempts.push(async move {
+            let res: anyhow::Result<_> = try {
+                ep.connect_with(client_config.clone(), addr, server_name)?
+                    .await?
+            };
+            (addr, res)
+        });
 
-    while let Some(stream) = new_conn.bi_streams.next().await {
-        let (mut send_stream, mut recv_stream) = stream?;
+        let next_attempt = tokio::time::sleep(delay.unwrap_or(Duration::MAX));
+        tokio::pin!(next_attempt);
+        loop {
+            tokio::select! {
+                Some((addr, res)) = attempts.next() => match res {
+                    Ok(new_conn) => return Ok((new_conn, addr)),
+                    Err(err) => {
+                        log::debug!("couldn't connect to {}: {:#}", addr, err);
+                        last_err = Some(err);
+                        if pending.peek().is_some() {
+                            break;
+                        }
+                    }
+                },
+                _ = &mut next_attempt, if delay.is_some() && pending.peek().is_some() => break,
+                else => break,
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("couldn't resolve to an address")))
+}
+
+/// Alternate IPv6 and IPv4 addresses, starting with the family of the first one.
+fn interleave_families(addrs: &mut Vec<SocketAddr>) {
+    let first_v6 = addrs.first().map_or(true, |a| a.is_ipv6());
+    let (mut first, mut second): (Vec<_>, Vec<_>) =
+        addrs.drain(..).partition(|a| a.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+    while let Some(addr) = first.pop() {
+        addrs.push(addr);
+        addrs.extend(second.pop());
+    }
+    addrs.extend(second.into_iter().rev());
+}
+
+/// Log the result of each registered route, collecting the names of the failed ones.
+fn handle_register_res(res: Vec<(String, RegisterRouteRes)>, failed: &mut FnvHashSet<String>) {
+    for (name, res) in res {
+        match res {
+            RegisterRouteRes::Ok => {
+                log::info!("route `{}` registered", name);
+                failed.remove(&name);
+            }
+            RegisterRouteRes::Err(err) if err.is_fatal() => {
+                log::error!("{}, not retried", err);
+                failed.remove(&name);
+            }
+            RegisterRouteRes::Err(err) => {
+                log::error!("{}", err);
+                failed.insert(name);
+            }
+        }
+    }
+}
+
+/// Register the routes added by a reload and unregister the removed ones, other route changes
+/// apply to the next streams of the route.
+fn reload_routes(old: &ClientConfig, new: &ClientConfig, control: &ControlSender) {
+    let unregister = old
+        .route
+        .iter()
+        .filter(|(name, route)| {
+            new.route
+                .get(*name)
+                .map_or(true, |r| r._type != route._type)
+        })
+        .map(|(name, _)| name.as_str().to_owned())
+        .collect::<Vec<_>>();
+    let register = new
+        .route
+        .iter()
+        .filter(|(name, route)| {
+            old.route
+                .get(*name)
+                .map_or(true, |r| r._type != route._type)
+        })
+        .map(|(name, route)| RegisterRoute {
+            name: name.as_str().to_owned(),
+            _type: route._type,
+        })
+        .collect::<Vec<_>>();
+
+    if !unregister.is_empty() {
+        control.send(Control::UnregisterRoute(unregister)).ok();
+    }
+    if !register.is_empty() {
+        control.send(Control::RegisterRoute(register)).ok();
+    }
+    if old.remote != new.remote || old.token != new.token || old.pool_size != new.pool_size {
+        log::info!("connection settings changed, they take effect on the next reconnect");
+    }
+}
+
+async fn authorize(
+    connection: &Connection,
+    config: &ClientConfig,
+    session: Option<u64>,
+    resume: Option<u64>,
+) -> anyhow::Result<((SendStream, RecvStream), u64)> {
+    let mut handshake_stream = connection.open_bi().await?;
+
+    // the token itself is never sent, only a proof of it for the challenge of the server
+    let hello = Hello::current();
+    write_proto::<_, 16>(&mut handshake_stream.0, hello.clone()).await?;
+    let server_hello = read_proto::<Hello, 16>(&mut handshake_stream.1).await?;
+    if !hello.compatible(&server_hello) {
+        let detail = format!(
+            "server protocol version {} is incompatible, {} to {} are supported",
+            server_hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        CloseReason::VersionMismatch.close(connection, &detail);
+        return Err(Closed {
+            reason: CloseReason::VersionMismatch,
+            detail,
+        }
+        .into());
+    }
+    let features = hello.common_features(&server_hello);
+    log::debug!(
+        "server protocol version {}, features {:#x}",
+        server_hello.version,
+        features
+    );
+
+    let login = Login { session, resume };
+    write_proto::<_, 32>(&mut handshake_stream.0, login.clone()).await?;
+    let challenge = read_proto::<Challenge, 64>(&mut handshake_stream.1).await?;
+    let proof = Token::prove(&config.token, &login.signed_message(&challenge.nonce));
+    write_proto::<_, 64>(&mut handshake_stream.0, Auth { proof }).await?;
+
+    // a failed authentication closes the connection, reported by `Closed`
+    let status = handshake_stream.1.read_u8().await?;
+    anyhow::ensure!(
+        status == CODE_AUTH_SUCCESS,
+        "unexpected handshake status {}",
+        status
+    );
+
+    Ok((handshake_stream, features))
+}
+
+async fn accept_streams(
+    connection: Connection,
+    mut bi_streams: IncomingBiStreams,
+    flows: Option<Arc<Flows>>,
+    config: watch::Receiver<Arc<ClientConfig>>,
+    active_streams: Arc<AtomicU64>,
+) -> anyhow::Result<()> {
+    while let Some(stream) = bi_streams.next().await {
+        let (send_stream, mut recv_stream) = stream?;
         let route_name = read_proto::<StreamStart, 32>(&mut recv_stream)
             .await?
             .route_name;
-        let route = config
-            .route
-            .get(&*route_name)
-            .ok_or_else(|| anyhow::anyhow!("Unexpected request route received: {}", &route_name))?;
+        let current = Arc::clone(&config.borrow());
+        let route = match current.route.get(&*route_name) {
+            Some(route) => route,
+            None => {
+                log::warn!("Unexpected request route received: {}", &route_name);
+                continue;
+            }
+        };
         let to = route.to;
 
         match route._type {
             RouteType::Tcp => {
+                let active_streams = Arc::clone(&active_streams);
                 tokio::spawn(async move {
                     let mut tcp_stream = tokio::io::BufStream::new(
                         TcpStream::connect(to)
@@ -117,19 +589,52 @@ pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Re
                             .unwrap(),
                     );
                     log::info!("tcp route connect on `{}`", to);
+                    active_streams.fetch_add(1, Ordering::Relaxed);
                     let mut quic_stream = tokio::io::BufStream::new(QuicStream {
                         bi: (send_stream, recv_stream),
                     });
                     tokio::io::copy_bidirectional(&mut tcp_stream, &mut quic_stream)
                         .await
                         .ok();
+                    active_streams.fetch_sub(1, Ordering::Relaxed);
                     log::info!("tcp route `{}` close", &route_name);
                 });
             }
             RouteType::Udp => {
-                let udp_buffer_size = route.udp_buffer.unwrap_or(2048);
-                let remote_address = new_conn.connection.remote_address();
+                let limit = Arc::new(SizeLimit::new(
+                    route.udp_buffer,
+                    route.udp_oversize,
+                    route_name.clone(),
+                ));
+                let remote_address = connection.remote_address();
+                let connection = connection.clone();
+                let flows = flows.clone();
+                let active_streams = Arc::clone(&active_streams);
                 tokio::spawn(async move {
+                    let id = match read_udp_stream(&mut recv_stream, flows.as_deref()).await {
+                        Ok(UdpStream::Flow(UdpFlow { id })) => id,
+                        Ok(UdpStream::Multiplexed { datagram }) => {
+                            let flows = flows.expect("multiplexing needs the flows");
+                            active_streams.fetch_add(1, Ordering::Relaxed);
+                            serve_udp_mux(
+                                connection,
+                                (send_stream, recv_stream),
+                                flows,
+                                datagram,
+                                to,
+                                limit,
+                            )
+                            .await;
+                            active_streams.fetch_sub(1, Ordering::Relaxed);
+                            log::info!("udp multiplexed route `{}` close", &route_name);
+                            return;
+                        }
+                        Err(err) => {
+                            log::debug!("failed to read udp stream header: {:?}", err);
+                            return;
+                        }
+                    };
+
                     let socket = Arc::new(UdpSocket::bind("[::]:0").await.unwrap());
                     socket
                         .connect(to)
@@ -139,17 +644,38 @@ pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Re
                         })
                         .unwrap();
 
+                    let mut flow_guard = None;
+                    let mut sender = FlowSender {
+                        stream: send_stream,
+                        datagram: None,
+                    };
+                    if let (Some(flows), Some(id)) = (flows, id) {
+                        flow_guard =
+                            Some(flows.insert(id, Arc::clone(&socket), None, Activity::new()));
+                        sender.datagram = Some((connection, id));
+                    }
+                    active_streams.fetch_add(1, Ordering::Relaxed);
+
+                    // the server finishes the stream of an evicted flow, which closes the socket
                     let socket_cloned = Arc::clone(&socket);
                     let route_name_cloned = route_name.clone();
-                    tokio::spawn(async move {
-                        let mut buf = BytesMut::with_capacity(udp_buffer_size);
+                    let limit_cloned = Arc::clone(&limit);
+                    let mut reader = tokio::spawn(async move {
+                        let _flow_guard = flow_guard;
+                        let mut buf = BytesMut::new();
                         let mut buf_reader = tokio::io::BufReader::new(recv_stream);
                         loop {
                             if let anyhow::Result::<_>::Err(_) = try {
-                                let len = buf_reader.read_varint_async().await?;
-                                buf.resize(len, 0);
-                                buf_reader.read_exact(&mut buf).await?;
-                                socket_cloned.send(&buf.copy_to_bytes(len)).await?;
+                                let packet = read_packet(
+                                    &mut buf_reader,
+                                    &mut buf,
+                                    &limit_cloned,
+                                    remote_address,
+                                )
+                                .await?;
+                                if let Some(packet) = packet {
+                                    socket_cloned.send(&packet).await?;
+                                }
                             } {
                                 log::info!(
                                     "udp data stream `{}` disconnect. (route: `{}` udp)",
@@ -161,17 +687,23 @@ pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Re
                         }
                     });
 
-                    let mut buf = BytesMut::with_capacity(udp_buffer_size);
-                    buf.resize(udp_buffer_size, 0);
+                    let mut buf = BytesMut::with_capacity(limit.recv_buffer_size());
 
-                    while let Ok((len, _addr)) = socket.recv_from(&mut buf).await {
+                    loop {
+                        buf.resize(limit.recv_buffer_size(), 0);
+                        let len = tokio::select! {
+                            res = socket.recv_from(&mut buf) => match res {
+                                Ok((len, _addr)) => len,
+                                Err(_) => break,
+                            },
+                            _ = &mut reader => break,
+                        };
+                        if !limit.check(len, to) {
+                            continue;
+                        }
                         let data = buf.copy_to_bytes(len);
-                        buf.resize(udp_buffer_size, 0);
 
-                        if let anyhow::Result::<_>::Err(_) = try {
-                            send_stream.write_varint(len as u32).await?;
-                            send_stream.write_all(&data).await?;
-                        } {
+                        if sender.send(&data).await.is_err() {
                             log::info!(
                                 "udp data stream `{}` disconnect. (route: `{}` udp)",
                                 remote_address,
@@ -180,14 +712,190 @@ pub async fn run(config: Arc<ClientConfig>, retry_num: &mut usize) -> anyhow::Re
                             break;
                         }
                     }
+                    active_streams.fetch_sub(1, Ordering::Relaxed);
                     log::info!("udp route `{}` close", &route_name);
                 });
             }
         }
     }
+    Ok(())
+}
 
-    ep.wait_idle().await;
-    log::info!("client closed");
+/// Serve a multiplexed udp stream, each flow gets its own socket connected to `to`. The flows
+/// are closed by the server or with the stream.
+async fn serve_udp_mux(
+    connection: Connection,
+    (mut send_stream, recv_stream): (SendStream, RecvStream),
+    flows: Arc<Flows>,
+    datagram: bool,
+    to: SocketAddr,
+    limit: Arc<SizeLimit>,
+) {
+    let remote_address = connection.remote_address();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<(u64, Bytes)>(64);
+    let writer = tokio::spawn(async move {
+        while let Some((id, packet)) = rx.recv().await {
+            if datagram && udp::send_datagram(&connection, id, &packet) {
+                continue;
+            }
+            if write_mux_packet(&mut send_stream, id, &packet)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
 
-    Ok(())
+    let mut mux_flows = FnvHashMap::<u64, MuxFlow>::default();
+    let mut buf = BytesMut::new();
+    let mut reader = tokio::io::BufReader::new(recv_stream);
+    loop {
+        let (id, packet) = match read_mux_frame(&mut reader, &mut buf, &limit, remote_address).await
+        {
+            Ok(MuxFrame::Packet(id, Some(packet))) => (id, packet),
+            Ok(MuxFrame::Packet(_, None)) => continue,
+            Ok(MuxFrame::Close(id)) => {
+                mux_flows.remove(&id);
+                continue;
+            }
+            Err(_) => break,
+        };
+        // a flow is opened by its first packet
+        if !mux_flows.contains_key(&id) {
+            match MuxFlow::open(id, to, &flows, &limit, tx.clone()).await {
+                Ok(flow) => {
+                    mux_flows.insert(id, flow);
+                }
+                Err(err) => {
+                    log::warn!("failed to connect to {}: {}", to, err);
+                    continue;
+                }
+            }
+        }
+        if let Some(flow) = mux_flows.get(&id) {
+            if let Err(err) = flow.socket.send(&packet).await {
+                log::debug!("failed to send udp packet to {}: {}", to, err);
+            }
+        }
+    }
+    writer.abort();
+}
+
+/// A flow of a multiplexed udp stream, its socket is closed when it is dropped.
+struct MuxFlow {
+    socket: Arc<UdpSocket>,
+    task: JoinHandle<()>,
+    _guard: FlowGuard,
+}
+
+impl MuxFlow {
+    async fn open(
+        id: u64,
+        to: SocketAddr,
+        flows: &Arc<Flows>,
+        limit: &Arc<SizeLimit>,
+        tx: tokio::sync::mpsc::Sender<(u64, Bytes)>,
+    ) -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind("[::]:0").await?);
+        socket.connect(to).await?;
+        let guard = flows.insert(id, Arc::clone(&socket), None, Activity::new());
+
+        let reader = Arc::clone(&socket);
+        let limit = Arc::clone(limit);
+        let task = tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(limit.recv_buffer_size());
+            loop {
+                buf.resize(limit.recv_buffer_size(), 0);
+                let len = match reader.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(_) => break,
+                };
+                if limit.check(len, to) && tx.send((id, buf.copy_to_bytes(len))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(MuxFlow {
+            socket,
+            task,
+            _guard: guard,
+        })
+    }
+}
+
+impl Drop for MuxFlow {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> ClientConfig {
+        toml::from_str(&format!(
+            "remote = \"localhost\"\ntoken = \"t\"\nroute = {{}}\nretry_interval = \"1s\"\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn secs(d: Option<Duration>) -> Option<u64> {
+        d.map(|d| d.as_secs())
+    }
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let config = config("max_retry_interval = \"5s\"\nretry_jitter = 0.0\nmax_retry = 5");
+        let mut backoff = Backoff::default();
+        let delays: Vec<_> = (0..6).map(|_| secs(backoff.next_delay(&config))).collect();
+        assert_eq!(delays, [Some(1), Some(2), Some(4), Some(5), Some(5), None]);
+    }
+
+    #[test]
+    fn backoff_huge_multiplier() {
+        let config = config("retry_multiplier = 1e300\nretry_jitter = 1.0");
+        let mut backoff = Backoff::default();
+        for _ in 0..10 {
+            let delay = backoff.next_delay(&config).unwrap();
+            assert!(delay <= DEFAULT_MAX_RETRY_INTERVAL * 2);
+        }
+    }
+
+    #[test]
+    fn interleave() {
+        let parse = |addrs: &[&str]| -> Vec<SocketAddr> {
+            addrs.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        let mut addrs = parse(&["[::1]:1", "[::2]:1", "[::3]:1", "1.0.0.1:1", "1.0.0.2:1"]);
+        interleave_families(&mut addrs);
+        assert_eq!(
+            addrs,
+            parse(&["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "[::3]:1"])
+        );
+
+        let mut addrs = parse(&["1.0.0.1:1", "[::1]:1", "[::2]:1", "[::3]:1"]);
+        interleave_families(&mut addrs);
+        assert_eq!(
+            addrs,
+            parse(&["1.0.0.1:1", "[::1]:1", "[::2]:1", "[::3]:1"])
+        );
+
+        let mut addrs = Vec::new();
+        interleave_families(&mut addrs);
+        assert!(addrs.is_empty());
+    }
+
+    #[test]
+    fn scale_clamps() {
+        let max = Duration::from_secs(10);
+        let d = Duration::from_secs(2);
+        assert_eq!(scale(d, 2.0, max), Duration::from_secs(4));
+        assert_eq!(scale(d, f64::INFINITY, max), max);
+        assert_eq!(scale(d, f64::NAN, max), Duration::ZERO);
+        assert_eq!(scale(d, -1.0, max), Duration::ZERO);
+    }
 }
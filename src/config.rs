@@ -1,12 +1,54 @@
+use std::fmt;
 use std::fs::read;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use anyhow::Context;
 use compact_str::CompactString;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
 pub fn configuration(config_file: PathBuf) -> anyhow::Result<Config> {
-    Ok(toml::from_slice(&read(config_file)?)?)
+    let config: Config = toml::from_slice(&read(config_file)?)?;
+    if let Some(interval) = &config.reload_interval {
+        anyhow::ensure!(
+            !interval.duration().is_zero(),
+            "`reload_interval` must be greater than zero"
+        );
+    }
+    if let Some(client) = &config.client {
+        if let Some(multiplier) = client.retry_multiplier {
+            anyhow::ensure!(
+                multiplier.is_finite() && multiplier >= 1.0,
+                "`retry_multiplier` must be a number of at least 1"
+            );
+        }
+        if let Some(jitter) = client.retry_jitter {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&jitter),
+                "`retry_jitter` must be between 0 and 1"
+            );
+        }
+    }
+    if let Some(server) = &config.server {
+        crate::quic::check_key_pair(&server.cert, &server.private_key).with_context(|| {
+            format!(
+                "`private_key` {} does not belong to `cert` {}",
+                server.private_key.path.display(),
+                server.cert.path.display()
+            )
+        })?;
+    }
+    if let Some(ClientConfig {
+        client_cert: Some(cert),
+        client_key: Some(key),
+        ..
+    }) = &config.client
+    {
+        crate::quic::check_key_pair(cert, key)
+            .context("`client_key` does not belong to `client_cert`")?;
+    }
+    Ok(config)
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -15,6 +57,8 @@ pub struct Config {
     pub client: Option<ClientConfig>,
     #[serde(default = "default_log_level")]
     pub log_level: log::LevelFilter,
+    /// check the configuration file for changes at this interval, it is always reloaded on SIGHUP.
+    pub reload_interval: Option<time_unit::TimeUnit>,
 }
 
 fn default_log_level() -> log::LevelFilter {
@@ -25,42 +69,328 @@ fn default_log_level() -> log::LevelFilter {
 pub struct ServerConfig {
     pub bind: SocketAddr,
     pub route: FnvHashMap<CompactString, ServerRoute>,
-    pub token: CompactString,
+    /// may register every route, clients limited to some routes use `credentials` instead.
+    pub token: Option<Token>,
+    /// the tokens of clients that may only register some routes, by client identity.
+    #[serde(default)]
+    pub credentials: FnvHashMap<CompactString, Credential>,
     pub max_concurrent_bidi_streams: Option<u32>,
+    #[serde(default)]
+    pub pool_strategy: PoolStrategy,
+    /// keep the routes of a disconnected client open this long for it to resume its session.
+    pub resume_timeout: Option<time_unit::TimeUnit>,
+    /// authenticate clients by their certificate.
+    pub client_auth: Option<ClientAuth>,
+    #[serde(default)]
+    pub lockout: Lockout,
 
-    #[serde(deserialize_with = "crate::quic::deserialize_cert")]
-    pub cert: Vec<rustls::Certificate>,
-    #[serde(deserialize_with = "crate::quic::deserialize_key")]
-    pub private_key: rustls::PrivateKey,
+    #[serde(deserialize_with = "crate::quic::deserialize_cert_file")]
+    pub cert: FromFile<Vec<rustls::Certificate>>,
+    #[serde(deserialize_with = "crate::quic::deserialize_key_file")]
+    pub private_key: FromFile<rustls::PrivateKey>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize)]
+impl ServerConfig {
+    /// The files the certificates are loaded from.
+    pub fn cert_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.cert.path.clone(), self.private_key.path.clone()];
+        files.extend(self.client_auth.as_ref().map(|c| c.ca.path.clone()));
+        files
+    }
+}
+
+/// A value loaded from a file, the path is kept so that the file can be watched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FromFile<T> {
+    pub path: PathBuf,
+    pub value: T,
+}
+
+impl<T> std::ops::Deref for FromFile<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
 pub struct ServerRoute {
     pub bind: SocketAddr,
     #[serde(rename = "type")]
     pub _type: RouteType,
+    /// the largest udp packet forwarded, 2048 bytes by default.
     pub udp_buffer: Option<usize>,
+    /// what to do with udp packets larger than `udp_buffer`.
+    #[serde(default)]
+    pub udp_oversize: OversizePolicy,
+    /// send udp payloads as QUIC datagrams instead of over a stream, so that a lost packet
+    /// doesn't delay the next ones. Payloads too large for a datagram still use the stream.
+    pub udp_datagram: Option<bool>,
+    /// carry every udp flow of the route over a single stream per client connection instead of
+    /// a stream per flow, for many short flows like DNS.
+    pub udp_multiplex: Option<bool>,
+    /// a udp flow without packets in either direction for this long is closed, 60s by default.
+    pub udp_idle_timeout: Option<time_unit::TimeUnit>,
+    /// new udp peers are dropped while this many flows are open, 1024 by default.
+    pub udp_max_flows: Option<usize>,
+    /// what a tcp connection does while every client is at its `max_concurrent_bidi_streams`.
+    #[serde(default)]
+    pub stream_admission: StreamAdmission,
+    /// a tcp connection still waiting for a stream after this long is rejected, 10s by default.
+    pub stream_queue_timeout: Option<time_unit::TimeUnit>,
+    /// how connections are spread over the clients serving this route.
+    #[serde(default)]
+    pub balance: Balance,
+}
+
+/// Clients with a certificate signed by `ca` are authenticated as the credential named after the
+/// common name of the certificate subject, the token they send is ignored.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct ClientAuth {
+    #[serde(deserialize_with = "crate::quic::deserialize_cert_file")]
+    pub ca: FromFile<Vec<rustls::Certificate>>,
+    /// reject clients without a certificate, default is true.
+    pub required: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct Credential {
+    /// none for clients only authenticated by their certificate.
+    pub token: Option<Token>,
+    /// the names of the routes this client may register.
+    pub routes: FnvHashSet<CompactString>,
+}
+
+/// A token of the server configuration, only the SHA-256 digest of its client key is kept, which
+/// verifies a proof of the token but can't produce one. It is written either as
+/// `scram-sha256:<hex digest>`, see `couscous hash`, or in plain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token([u8; 32]);
+
+impl Token {
+    pub fn digest(token: &str) -> Self {
+        Token(sha256(&client_key(token)))
+    }
+
+    /// Prove to the server that the client holds `token` for `msg`: the client key of the token
+    /// XORed with the HMAC of `msg` keyed by the digest, as in SCRAM.
+    pub fn prove(token: &str, msg: &[u8]) -> Vec<u8> {
+        let key = client_key(token);
+        let signature = Token::digest(token).signature(msg);
+        key.iter()
+            .zip(signature.as_ref())
+            .map(|(k, s)| k ^ s)
+            .collect()
+    }
+
+    /// Check a proof of the token for `msg` by recovering the client key it holds, in constant
+    /// time.
+    pub fn verify(&self, msg: &[u8], proof: &[u8]) -> bool {
+        if proof.len() != self.0.len() {
+            return false;
+        }
+        let signature = self.signature(msg);
+        let key: Vec<u8> = proof
+            .iter()
+            .zip(signature.as_ref())
+            .map(|(p, s)| p ^ s)
+            .collect();
+        ring::constant_time::verify_slices_are_equal(&sha256(&key), &self.0).is_ok()
+    }
+
+    fn signature(&self, msg: &[u8]) -> ring::hmac::Tag {
+        ring::hmac::sign(&ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &self.0), msg)
+    }
+}
+
+fn client_key(token: &str) -> [u8; 32] {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, token.as_bytes());
+    ring::hmac::sign(&key, b"couscous client key")
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+impl FromStr for Token {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("scram-sha256:") {
+            Ok(Token(parse_sha256(hex)?))
+        } else if s.starts_with("sha256:") {
+            anyhow::bail!("`sha256:` token digests are no longer accepted, hash the token again")
+        } else {
+            Ok(Token::digest(s))
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("scram-sha256:")?;
+        write_hex(f, &self.0)
+    }
+}
+
+/// A pinned server certificate, written as `cert-sha256:<hex digest>` of the whole certificate
+/// or `spki-sha256:<hex digest>` of its public key, which stays the same when the certificate is
+/// renewed with the same key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pin {
+    Cert([u8; 32]),
+    Spki([u8; 32]),
+}
+
+impl Pin {
+    pub fn spki(cert: &rustls::Certificate) -> anyhow::Result<Self> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)?;
+        Ok(Pin::Spki(sha256(parsed.tbs_certificate.subject_pki.raw)))
+    }
+
+    pub fn matches(&self, cert: &rustls::Certificate) -> bool {
+        match self {
+            Pin::Cert(digest) => *digest == sha256(&cert.0),
+            Pin::Spki(_) => Pin::spki(cert).map_or(false, |pin| pin == *self),
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("cert-sha256:") {
+            Ok(Pin::Cert(parse_sha256(hex)?))
+        } else if let Some(hex) = s.strip_prefix("spki-sha256:") {
+            Ok(Pin::Spki(parse_sha256(hex)?))
+        } else {
+            anyhow::bail!("a pin starts with `cert-sha256:` or `spki-sha256:`")
+        }
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pin::Cert(digest) => {
+                f.write_str("cert-sha256:")?;
+                write_hex(f, digest)
+            }
+            Pin::Spki(digest) => {
+                f.write_str("spki-sha256:")?;
+                write_hex(f, digest)
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Pin {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = CompactString::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    digest.as_ref().try_into().unwrap()
+}
+
+fn parse_sha256(hex: &str) -> anyhow::Result<[u8; 32]> {
+    anyhow::ensure!(
+        hex.len() == 64 && hex.is_ascii(),
+        "a sha256 digest is 64 hex digits"
+    );
+    let mut digest = [0; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(digest)
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+}
+
+impl<'de> serde::Deserialize<'de> for Token {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = CompactString::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Addresses failing to authenticate `max_failures` times are banned for `ban`.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Lockout {
+    /// default is 5.
+    pub max_failures: Option<u32>,
+    /// failures older than the ban are forgotten too, default is 5m.
+    pub ban: Option<time_unit::TimeUnit>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct ClientConfig {
-    pub remote: CompactString,
+    /// one server address or a list of them, tried in order until one can be connected. An
+    /// address is `host:port`, `[ipv6]:port` or a host alone for the default port 4000.
+    #[serde(deserialize_with = "deserialize_remote")]
+    pub remote: Vec<CompactString>,
+    /// the name the server certificate is verified against, the host of the remote by default.
+    pub server_name: Option<CompactString>,
+    /// trust the certificates of the system, default is false.
+    pub native_roots: Option<bool>,
+    /// the server certificate must match one of these, see `Pin`. Without `cert` or
+    /// `native_roots`, a pinned certificate is trusted on its own.
+    #[serde(default)]
+    pub pins: Vec<Pin>,
+    #[serde(default)]
+    pub connect_strategy: ConnectStrategy,
     pub route: FnvHashMap<CompactString, ClientRoute>,
     pub token: CompactString,
+    /// the first reconnection delay, the client doesn't reconnect without it.
     pub retry_interval: Option<time_unit::TimeUnit>,
     pub max_retry: Option<usize>,
+    /// the delay is multiplied by this after each failed attempt, default is 2.
+    pub retry_multiplier: Option<f64>,
+    /// the delay doesn't grow beyond this, default is 5m.
+    pub max_retry_interval: Option<time_unit::TimeUnit>,
+    /// each delay is randomly shortened or lengthened by up to this fraction, default is 0.2.
+    pub retry_jitter: Option<f64>,
+    /// the delay and the number of retries are reset once connected for this long, default is 1m.
+    pub retry_reset_after: Option<time_unit::TimeUnit>,
     pub max_concurrent_bidi_streams: Option<u32>,
+    /// the server may grow `max_concurrent_bidi_streams` of a connection up to this, 1024 by
+    /// default.
+    pub max_grown_bidi_streams: Option<u32>,
+    /// number of connections opened to the server, data streams are spread over them.
+    pub pool_size: Option<usize>,
 
-    #[serde(deserialize_with = "crate::quic::deserialize_cert")]
+    /// trusted certificates of the server, in addition to the system ones if `native_roots`.
+    #[serde(default, deserialize_with = "crate::quic::deserialize_cert")]
     pub cert: Vec<rustls::Certificate>,
+    /// certificate presented to a server with `client_auth`.
+    #[serde(default, deserialize_with = "crate::quic::deserialize_optional_cert")]
+    pub client_cert: Option<Vec<rustls::Certificate>>,
+    #[serde(default, deserialize_with = "crate::quic::deserialize_optional_key")]
+    pub client_key: Option<rustls::PrivateKey>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
 pub struct ClientRoute {
     pub to: SocketAddr,
     #[serde(rename = "type")]
     pub _type: RouteType,
+    /// the largest udp packet forwarded, 2048 bytes by default.
     pub udp_buffer: Option<usize>,
+    /// what to do with udp packets larger than `udp_buffer`.
+    #[serde(default)]
+    pub udp_oversize: OversizePolicy,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, bincode::Encode, bincode::Decode)]
@@ -71,6 +401,145 @@ pub enum RouteType {
     Udp,
 }
 
+/// How the server picks a connection from a client's pool for a new stream.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum PoolStrategy {
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    #[serde(rename = "least_streams")]
+    LeastStreams,
+}
+
+impl Default for PoolStrategy {
+    fn default() -> Self {
+        PoolStrategy::RoundRobin
+    }
+}
+
+/// How the client tries the resolved addresses of a remote.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum ConnectStrategy {
+    /// One address after the other.
+    #[serde(rename = "ordered")]
+    Ordered,
+    /// Start connecting to the next address, alternating IPv6 and IPv4, if the previous one
+    /// didn't succeed in 250ms, keeping the first connection that succeeds.
+    #[serde(rename = "happy_eyeballs")]
+    HappyEyeballs,
+}
+
+impl Default for ConnectStrategy {
+    fn default() -> Self {
+        ConnectStrategy::HappyEyeballs
+    }
+}
+
+fn deserialize_remote<'de, D>(d: D) -> Result<Vec<CompactString>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Remote {
+        One(CompactString),
+        List(Vec<CompactString>),
+    }
+
+    let remotes = match serde::Deserialize::deserialize(d)? {
+        Remote::One(remote) => vec![remote],
+        Remote::List(remotes) if remotes.is_empty() => {
+            return Err(serde::de::Error::custom("`remote` is empty"))
+        }
+        Remote::List(remotes) => remotes,
+    };
+    for remote in &remotes {
+        split_host_port(remote).map_err(serde::de::Error::custom)?;
+    }
+    Ok(remotes)
+}
+
+pub const DEFAULT_PORT: u16 = 4000;
+
+/// Split a remote address into its host, without the brackets of an IPv6 address, and port.
+pub fn split_host_port(remote: &str) -> anyhow::Result<(&str, u16)> {
+    if let Some(rest) = remote.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow::anyhow!("unclosed `[` in `{}`", remote))?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Ok((host, port.parse()?)),
+            None if rest.is_empty() => Ok((host, DEFAULT_PORT)),
+            None => anyhow::bail!("invalid address `{}`", remote),
+        };
+    }
+    // a bare IPv6 address has several colons
+    if remote.parse::<std::net::Ipv6Addr>().is_ok() {
+        return Ok((remote, DEFAULT_PORT));
+    }
+    match remote.split_once(':') {
+        Some((host, port)) => Ok((host, port.parse()?)),
+        None => Ok((remote, DEFAULT_PORT)),
+    }
+}
+
+/// How the server picks one of the clients serving a route for a new connection.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum Balance {
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    #[serde(rename = "random")]
+    Random,
+    #[serde(rename = "least_connections")]
+    LeastConnections,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Balance::RoundRobin
+    }
+}
+
+/// What a udp route does with a packet larger than its `udp_buffer`, every such packet is
+/// counted.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum OversizePolicy {
+    #[serde(rename = "drop")]
+    Drop,
+    /// drop it with a warning.
+    #[serde(rename = "log")]
+    Log,
+    /// forward it anyway, leaving it to the network to fragment it.
+    #[serde(rename = "fragment")]
+    Fragment,
+}
+
+impl Default for OversizePolicy {
+    fn default() -> Self {
+        OversizePolicy::Log
+    }
+}
+
+/// What a tcp route does with a connection while no stream to a client can be opened, rejected
+/// connections are reset and counted.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum StreamAdmission {
+    /// wait for a stream up to `stream_queue_timeout`.
+    #[serde(rename = "queue")]
+    Queue,
+    /// reject the connection at once.
+    #[serde(rename = "reject")]
+    Reject,
+    /// ask the client to allow more streams, then wait like `queue`.
+    #[serde(rename = "grow")]
+    Grow,
+}
+
+impl Default for StreamAdmission {
+    fn default() -> Self {
+        StreamAdmission::Queue
+    }
+}
+
 mod time_unit {
     use std::fmt;
     use std::fmt::{Debug, Formatter};
@@ -80,7 +549,7 @@ mod time_unit {
     use serde::de::Visitor;
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub struct TimeUnit(Duration);
 
     impl TimeUnit {
@@ -141,3 +610,75 @@ mod time_unit {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_port() {
+        assert_eq!(split_host_port("host").unwrap(), ("host", DEFAULT_PORT));
+        assert_eq!(split_host_port("host:4433").unwrap(), ("host", 4433));
+        assert_eq!(split_host_port("[::1]:4433").unwrap(), ("::1", 4433));
+        assert_eq!(split_host_port("[::1]").unwrap(), ("::1", DEFAULT_PORT));
+        assert_eq!(split_host_port("::1").unwrap(), ("::1", DEFAULT_PORT));
+        assert!(split_host_port("host:port").is_err());
+        assert!(split_host_port("host:70000").is_err());
+        assert!(split_host_port("[::1").is_err());
+        assert!(split_host_port("[::1]4433").is_err());
+    }
+
+    #[test]
+    fn pin() {
+        let hex = "ab".repeat(32);
+        let cert: Pin = format!("cert-sha256:{}", hex).parse().unwrap();
+        assert_eq!(cert, Pin::Cert([0xab; 32]));
+        assert_eq!(cert.to_string(), format!("cert-sha256:{}", hex));
+        let spki: Pin = format!("spki-sha256:{}", hex).parse().unwrap();
+        assert_eq!(spki, Pin::Spki([0xab; 32]));
+        assert_eq!(spki.to_string(), format!("spki-sha256:{}", hex));
+
+        assert!(format!("sha256:{}", hex).parse::<Pin>().is_err());
+        assert!("cert-sha256:abab".parse::<Pin>().is_err());
+        assert!(format!("spki-sha256:{}", "x".repeat(64))
+            .parse::<Pin>()
+            .is_err());
+    }
+
+    #[test]
+    fn token_plain_or_digest() {
+        let plain: Token = "secret".parse().unwrap();
+        assert_eq!(plain, Token::digest("secret"));
+        let hashed: Token = plain.to_string().parse().unwrap();
+        assert_eq!(hashed, plain);
+        assert!(plain.to_string().starts_with("scram-sha256:"));
+        assert_ne!(plain, Token::digest("other"));
+    }
+
+    #[test]
+    fn token_proof() {
+        let token = Token::digest("secret");
+        let proof = Token::prove("secret", b"msg");
+        assert!(token.verify(b"msg", &proof));
+        assert!(!token.verify(b"other msg", &proof));
+        assert!(!token.verify(b"msg", &Token::prove("other", b"msg")));
+        assert!(!token.verify(b"msg", &proof[..31]));
+        // the digest alone doesn't make a proof
+        let forged = token.signature(b"msg");
+        assert!(!token.verify(b"msg", forged.as_ref()));
+    }
+
+    #[test]
+    fn token_bad_digest() {
+        let old = format!("sha256:{}", "0".repeat(64));
+        assert!(old.parse::<Token>().is_err());
+        assert!("scram-sha256:".parse::<Token>().is_err());
+        assert!("scram-sha256:abc".parse::<Token>().is_err());
+        assert!(format!("scram-sha256:{}", "zz".repeat(32))
+            .parse::<Token>()
+            .is_err());
+        assert!(format!("scram-sha256:{}é", "0".repeat(62))
+            .parse::<Token>()
+            .is_err());
+    }
+}
@@ -3,17 +3,23 @@
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::proto::Closed;
+
+mod auth;
 pub mod client;
 pub mod config;
+mod pool;
 pub mod proto;
 mod quic;
+mod reload;
 pub mod server;
+mod udp;
 
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
 pub async fn run(conf: PathBuf) -> anyhow::Result<()> {
-    let config = crate::config::configuration(conf)?;
+    let config = crate::config::configuration(conf.clone())?;
 
     setup_logger(config.log_level)?;
 
@@ -24,31 +30,67 @@ pub async fn run(conf: PathBuf) -> anyhow::Result<()> {
         (Some(_), Some(_)) => {
             anyhow::bail!("cannot be both a server and a client")
         }
-        (Some(server), None) => server::run(Arc::new(server.clone())).await,
+        (Some(server), None) => {
+            let server = reload::spawn(
+                conf,
+                &config,
+                server.clone(),
+                |c| {
+                    c.server
+                        .filter(|_| c.client.is_none())
+                        .ok_or_else(|| anyhow::anyhow!("expect a server configuration"))
+                },
+                |s| s.cert_files(),
+            );
+            server::run(server).await
+        }
         (None, Some(client)) => {
-            let mut retry_num = 0;
+            let client = reload::spawn(
+                conf,
+                &config,
+                client.clone(),
+                |c| {
+                    c.client
+                        .filter(|_| c.server.is_none())
+                        .ok_or_else(|| anyhow::anyhow!("expect a client configuration"))
+                },
+                |_| Vec::new(),
+            );
+            let mut backoff = client::Backoff::default();
+            let mut session = None;
             loop {
-                if let Err(err) = client::run(Arc::new(client.clone()), &mut retry_num).await {
-                    log::error!("{}", err);
-                    let time = if let Some(time) = &client.retry_interval {
-                        *time.duration()
-                    } else {
-                        break Ok(());
-                    };
-
-                    retry_num += 1;
-
-                    if let Some(max) = client.max_retry {
-                        log::info!("start {}/{} retries after {:?}...", retry_num, max, time);
-                        if retry_num > max {
-                            log::warn!("retry up to the maximum number of times, stop.");
+                // a connection closed by the server is retried like a failed one
+                match client::run(client.clone(), &mut backoff, &mut session).await {
+                    Ok(()) => log::warn!("disconnected from the server"),
+                    Err(err) => {
+                        match Closed::from_error(&err) {
+                            Some(closed) => log::error!("{}", closed),
+                            None => log::error!("{:#}", err),
+                        }
+                        if client::is_fatal(&err) {
+                            log::error!("not retrying, fix the configuration first.");
                             break Ok(());
                         }
-                    } else {
-                        log::info!("start the {}nd retry after {:?}...", retry_num, time);
                     }
-                    tokio::time::sleep(time).await;
                 }
+
+                let client = Arc::clone(&client.borrow());
+                let time = match backoff.next_delay(&client) {
+                    Some(time) => time,
+                    None if client.retry_interval.is_some() => {
+                        log::warn!("retry up to the maximum number of times, stop.");
+                        break Ok(());
+                    }
+                    None => break Ok(()),
+                };
+
+                match client.max_retry {
+                    Some(max) => {
+                        log::info!("retry {}/{} after {:?}...", backoff.retries(), max, time)
+                    }
+                    None => log::info!("retry #{} after {:?}...", backoff.retries(), time),
+                }
+                tokio::time::sleep(time).await;
             }
         }
     }
@@ -66,8 +108,10 @@ fn setup_logger(level: log::LevelFilter) -> Result<(), fern::InitError> {
             ))
         })
         .filter(|metadata| metadata.target() != "tokio_graceful_shutdown::shutdown_token")
-        .level(level)
+        .level(log::LevelFilter::Trace)
         .chain(std::io::stdout())
         .apply()?;
+    // the level can be changed by a configuration reload
+    log::set_max_level(level);
     Ok(())
 }
@@ -1,4 +1,10 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use couscous::config::Pin;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
 
 #[tokio::main]
 async fn main() {
@@ -8,15 +14,56 @@ async fn main() {
         SubCommand::Run(args) => {
             couscous::run(args.conf).await.unwrap();
         }
+        SubCommand::Hash(args) => {
+            println!("{}", couscous::config::Token::digest(&args.token));
+        }
         SubCommand::RcGen(args) => {
             let out = args.out_dir.unwrap_or_else(|| PathBuf::from("."));
-            let cert = rcgen::generate_simple_self_signed(args.hosts).unwrap();
-            std::fs::write(out.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
-            std::fs::write(out.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+            if args.ca {
+                let mut params = CertificateParams::new(args.hosts);
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params
+                    .distinguished_name
+                    .push(DnType::CommonName, "couscous client ca");
+                let ca = Certificate::from_params(params).unwrap();
+                std::fs::write(out.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+                std::fs::write(out.join("ca-key.pem"), ca.serialize_private_key_pem()).unwrap();
+            } else if let Some(name) = args.client {
+                let ca = load_ca(&out);
+                let mut params = CertificateParams::new(args.hosts);
+                params.distinguished_name.push(DnType::CommonName, &name);
+                params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+                let cert = Certificate::from_params(params).unwrap();
+                std::fs::write(
+                    out.join(format!("{}.pem", name)),
+                    cert.serialize_pem_with_signer(&ca).unwrap(),
+                )
+                .unwrap();
+                std::fs::write(
+                    out.join(format!("{}-key.pem", name)),
+                    cert.serialize_private_key_pem(),
+                )
+                .unwrap();
+            } else {
+                let cert = rcgen::generate_simple_self_signed(args.hosts).unwrap();
+                std::fs::write(out.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+                std::fs::write(out.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+                let der = rustls::Certificate(cert.serialize_der().unwrap());
+                println!("pin: {}", Pin::spki(&der).unwrap());
+            }
         }
     }
 }
 
+/// Load the CA generated by `rcgen --ca` in `dir`.
+fn load_ca(dir: &Path) -> Certificate {
+    let cert = std::fs::read_to_string(dir.join("ca.pem")).expect("failed to read ca.pem");
+    let key = std::fs::read_to_string(dir.join("ca-key.pem")).expect("failed to read ca-key.pem");
+    let params = CertificateParams::from_ca_cert_pem(&cert, KeyPair::from_pem(&key).unwrap())
+        .expect("invalid CA certificate");
+    Certificate::from_params(params).unwrap()
+}
+
 /// arguments
 #[derive(argh::FromArgs)]
 struct Arguments {
@@ -29,6 +76,7 @@ struct Arguments {
 enum SubCommand {
     Run(Run),
     RcGen(RcGen),
+    Hash(Hash),
 }
 
 #[derive(argh::FromArgs)]
@@ -48,7 +96,24 @@ struct RcGen {
     /// hosts
     hosts: Vec<String>,
 
+    #[argh(switch)]
+    /// gen a CA for client certificates (ca.pem, ca-key.pem)
+    ca: bool,
+
+    #[argh(option)]
+    /// gen a client certificate for this identity, signed by the CA in the out dir
+    client: Option<String>,
+
     #[argh(option, short = 'o')]
     /// out dir
     out_dir: Option<PathBuf>,
 }
+
+#[derive(argh::FromArgs)]
+#[argh(subcommand, name = "hash")]
+/// hash a token for the server configuration
+struct Hash {
+    #[argh(positional)]
+    /// token
+    token: String,
+}
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures_util::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+
+use crate::config::{Balance, PoolStrategy};
+use crate::proto::{CloseReason, Control, ControlSender};
+use crate::udp::Flows;
+
+/// The authenticated connections of one client, data streams are spread over them.
+pub struct ConnPool {
+    strategy: PoolStrategy,
+    conns: RwLock<Vec<PooledConn>>,
+    next: AtomicUsize,
+    /// The control stream of the client, if it supports `Control::GrowStreams`.
+    control: Mutex<Option<ControlSender>>,
+}
+
+#[derive(Clone)]
+pub struct PooledConn {
+    pub conn: Arc<Connection>,
+    /// The udp flows of the connection, if it supports datagrams.
+    pub flows: Option<Arc<Flows>>,
+    streams: Arc<AtomicU64>,
+}
+
+/// The clients serving one route, new connections of the route are balanced over them.
+pub struct Backends {
+    balance: Balance,
+    pools: RwLock<Vec<(u64, Arc<ConnPool>)>>,
+    next: AtomicUsize,
+}
+
+/// Counts as an active stream of its connection until dropped.
+pub struct StreamGuard(Arc<AtomicU64>);
+
+impl ConnPool {
+    pub fn new(strategy: PoolStrategy) -> Self {
+        ConnPool {
+            strategy,
+            conns: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            control: Mutex::new(None),
+        }
+    }
+
+    pub fn set_control(&self, control: Option<ControlSender>) {
+        *self.control.lock() = control;
+    }
+
+    /// Ask the client to allow `n` more streams on each connection, if it supports it.
+    pub fn grow_streams(&self, n: u32) -> bool {
+        match &*self.control.lock() {
+            Some(control) => control.send(Control::GrowStreams(n)).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, conn: Arc<Connection>, flows: Option<Arc<Flows>>) {
+        self.conns.write().push(PooledConn {
+            conn,
+            flows,
+            streams: Arc::new(AtomicU64::new(0)),
+        });
+    }
+
+    pub fn remove(&self, conn: &Connection) {
+        let id = conn.stable_id();
+        self.conns.write().retain(|c| c.conn.stable_id() != id);
+    }
+
+    /// Replace every connection of the pool with `conn`, closing them for `reason`.
+    pub fn take_over(&self, conn: Arc<Connection>, flows: Option<Arc<Flows>>, reason: CloseReason) {
+        let mut conns = self.conns.write();
+        for c in conns.drain(..) {
+            reason.close(&c.conn, "");
+        }
+        conns.push(PooledConn {
+            conn,
+            flows,
+            streams: Arc::new(AtomicU64::new(0)),
+        });
+    }
+
+    /// Close every connection of the pool.
+    pub fn close(&self, reason: CloseReason) {
+        for c in self.conns.write().drain(..) {
+            reason.close(&c.conn, "");
+        }
+    }
+
+    pub fn active_streams(&self) -> u64 {
+        self.conns
+            .read()
+            .iter()
+            .map(|c| c.streams.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Pick a connection according to the pool strategy.
+    pub fn pick(&self) -> Option<PooledConn> {
+        let conns = self.conns.read();
+        if conns.is_empty() {
+            return None;
+        }
+        match self.strategy {
+            PoolStrategy::RoundRobin => {
+                let i = self.next.fetch_add(1, Ordering::Relaxed) % conns.len();
+                Some(conns[i].clone())
+            }
+            PoolStrategy::LeastStreams => conns
+                .iter()
+                .min_by_key(|c| c.streams.load(Ordering::Relaxed))
+                .cloned(),
+        }
+    }
+}
+
+impl Backends {
+    pub fn new(balance: Balance) -> Self {
+        Backends {
+            balance,
+            pools: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add the pool of a session, a session is only added once.
+    pub fn insert(&self, session: u64, pool: Arc<ConnPool>) {
+        let mut pools = self.pools.write();
+        if pools.iter().all(|(id, _)| *id != session) {
+            pools.push((session, pool));
+        }
+    }
+
+    /// Remove the pool of a session, returning whether any client still serves the route.
+    pub fn remove(&self, session: u64) -> bool {
+        let mut pools = self.pools.write();
+        pools.retain(|(id, _)| *id != session);
+        !pools.is_empty()
+    }
+
+    /// Add every client of `other`.
+    pub fn extend(&self, other: &Backends) {
+        for (session, pool) in other.pools.read().iter() {
+            self.insert(*session, Arc::clone(pool));
+        }
+    }
+
+    /// Open a stream to a client picked by the balance strategy, failing over to the other
+    /// clients if it has no connection left or the stream can't be opened.
+    pub async fn open_bi(&self) -> Option<(PooledConn, SendStream, RecvStream, StreamGuard)> {
+        for pool in self.candidates() {
+            let conn = match pool.pick() {
+                Some(conn) => conn,
+                None => continue,
+            };
+            match conn.open_bi().await {
+                Ok((send_stream, recv_stream, guard)) => {
+                    return Some((conn, send_stream, recv_stream, guard))
+                }
+                Err(err) => log::debug!(
+                    "failed to open stream to {}: {}",
+                    conn.conn.remote_address(),
+                    err
+                ),
+            }
+        }
+        None
+    }
+
+    /// Like `open_bi`, but only takes a stream that can be opened without waiting for a client
+    /// to allow more.
+    pub fn try_open_bi(&self) -> Option<(PooledConn, SendStream, RecvStream, StreamGuard)> {
+        for pool in self.candidates() {
+            let conn = match pool.pick() {
+                Some(conn) => conn,
+                None => continue,
+            };
+            match conn.open_bi().now_or_never() {
+                Some(Ok((send_stream, recv_stream, guard))) => {
+                    return Some((conn, send_stream, recv_stream, guard))
+                }
+                Some(Err(err)) => log::debug!(
+                    "failed to open stream to {}: {}",
+                    conn.conn.remote_address(),
+                    err
+                ),
+                None => {}
+            }
+        }
+        None
+    }
+
+    /// Ask the first client picked by the balance strategy that supports it to allow `n` more
+    /// streams, returning its connection to wait on for the stream.
+    pub fn grow_streams(&self, n: u32) -> Option<PooledConn> {
+        self.candidates().iter().find_map(|pool| {
+            let conn = pool.pick()?;
+            pool.grow_streams(n).then(|| conn)
+        })
+    }
+
+    /// A connection of a client picked by the balance strategy, for streams opened later.
+    pub fn pick(&self) -> Option<PooledConn> {
+        self.candidates().iter().find_map(|pool| pool.pick())
+    }
+
+    /// The pools in the order they should be tried.
+    fn candidates(&self) -> Vec<Arc<ConnPool>> {
+        let mut pools = self
+            .pools
+            .read()
+            .iter()
+            .map(|(_, pool)| Arc::clone(pool))
+            .collect::<Vec<_>>();
+        if pools.is_empty() {
+            return pools;
+        }
+        match self.balance {
+            Balance::RoundRobin => {
+                let i = self.next.fetch_add(1, Ordering::Relaxed) % pools.len();
+                pools.rotate_left(i);
+            }
+            Balance::Random => {
+                let i = ring::rand::generate::<[u8; 8]>(&ring::rand::SystemRandom::new())
+                    .map_or(0, |r| u64::from_le_bytes(r.expose()) as usize);
+                let len = pools.len();
+                pools.rotate_left(i % len);
+            }
+            Balance::LeastConnections => pools.sort_by_key(|pool| pool.active_streams()),
+        }
+        pools
+    }
+}
+
+impl PooledConn {
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream, StreamGuard), ConnectionError> {
+        let (send_stream, recv_stream) = self.conn.open_bi().await?;
+        Ok((send_stream, recv_stream, self.guard()))
+    }
+
+    fn guard(&self) -> StreamGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(Arc::clone(&self.streams))
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,23 +1,234 @@
+use std::fmt;
+
 use bincode::{Decode, Encode};
 use integer_encoding::VarIntAsyncReader;
 use once_cell::sync::Lazy;
-use quinn::{RecvStream, SendStream};
+use quinn::{
+    Connection, ConnectionError, ReadError, ReadExactError, RecvStream, SendStream, VarInt,
+    WriteError,
+};
 use smallvec::SmallVec;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, unbounded_channel, UnboundedSender};
 
 use crate::config::RouteType;
 
 pub static BINCODE_CONFIG: Lazy<bincode::config::Configuration> =
     Lazy::new(bincode::config::Configuration::standard);
 
-pub const CODE_SHUTDOWN: u8 = 0;
-pub const CODE_AUTH_FAILED: u8 = 10;
-
 pub const CODE_AUTH_SUCCESS: u8 = 11;
 
+/// Why a connection is closed, sent as the code of the QUIC `ApplicationClose` with a human
+/// readable detail as its reason.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CloseReason {
+    Shutdown,
+    AuthFailed,
+    SessionNotFound,
+    /// Another connection of the same client resumed the session.
+    SessionTakenOver,
+    VersionMismatch,
+    /// A code unknown to this version.
+    Other(u64),
+}
+
+impl CloseReason {
+    pub fn code(self) -> u64 {
+        match self {
+            CloseReason::Shutdown => 0,
+            CloseReason::AuthFailed => 10,
+            CloseReason::SessionNotFound => 12,
+            CloseReason::SessionTakenOver => 13,
+            CloseReason::VersionMismatch => 14,
+            CloseReason::Other(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            0 => CloseReason::Shutdown,
+            10 => CloseReason::AuthFailed,
+            12 => CloseReason::SessionNotFound,
+            13 => CloseReason::SessionTakenOver,
+            14 => CloseReason::VersionMismatch,
+            code => CloseReason::Other(code),
+        }
+    }
+
+    /// Whether connecting again can't succeed until the configuration is changed.
+    pub fn is_fatal(self) -> bool {
+        matches!(self, CloseReason::AuthFailed | CloseReason::VersionMismatch)
+    }
+
+    pub fn close(self, connection: &Connection, detail: &str) {
+        connection.close(self.into(), detail.as_bytes());
+    }
+}
+
+impl From<CloseReason> for VarInt {
+    fn from(reason: CloseReason) -> Self {
+        VarInt::from_u64(reason.code()).unwrap_or(VarInt::MAX)
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Shutdown => f.write_str("shutdown"),
+            CloseReason::AuthFailed => f.write_str("authentication failed"),
+            CloseReason::SessionNotFound => f.write_str("session not found"),
+            CloseReason::SessionTakenOver => f.write_str("session taken over"),
+            CloseReason::VersionMismatch => f.write_str("incompatible protocol version"),
+            CloseReason::Other(code) => write!(f, "unknown reason {}", code),
+        }
+    }
+}
+
+/// The connection was closed, usually by the peer.
+#[derive(Clone, Debug)]
+pub struct Closed {
+    pub reason: CloseReason,
+    pub detail: String,
+}
+
+impl Closed {
+    /// Find the `ApplicationClose` of the peer behind the error of a connection or stream, or a
+    /// `Closed` returned as is after closing the connection locally.
+    pub fn from_error(err: &anyhow::Error) -> Option<Closed> {
+        if let Some(closed) = err.chain().find_map(|e| e.downcast_ref::<Closed>()) {
+            return Some(closed.clone());
+        }
+        err.chain().find_map(|e| match connection_error(e)? {
+            ConnectionError::ApplicationClosed(close) => Some(Closed {
+                reason: CloseReason::from_code(close.error_code.into_inner()),
+                detail: String::from_utf8_lossy(&close.reason).into_owned(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+fn connection_error<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a ConnectionError> {
+    // stream errors are wrapped into io errors by the `AsyncRead` and `AsyncWrite` impls
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return connection_error(e.get_ref()?);
+    }
+    if let Some(e) = e.downcast_ref::<ConnectionError>() {
+        return Some(e);
+    }
+    match e.downcast_ref::<ReadExactError>() {
+        Some(ReadExactError::ReadError(e)) => return connection_error(e),
+        Some(_) => return None,
+        None => {}
+    }
+    match (
+        e.downcast_ref::<ReadError>(),
+        e.downcast_ref::<WriteError>(),
+    ) {
+        (Some(ReadError::ConnectionLost(e)), _) | (_, Some(WriteError::ConnectionLost(e))) => {
+            Some(e)
+        }
+        _ => None,
+    }
+}
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection closed: {}", self.reason)?;
+        if !self.detail.is_empty() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// Bumped whenever the layout or meaning of a message changes, 2 changed the `Auth` proof.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest version of the peer this one can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// Optional features supported by this side, as a set of `FEATURE_*` bits. Both sides only use
+/// the features advertised by the other one.
+pub const FEATURES: u64 = FEATURE_UDP_DATAGRAM | FEATURE_UDP_MULTIPLEX | FEATURE_GROW_STREAMS;
+/// Udp streams start with a `UdpFlow`, and the payloads of a flow with an id may be sent as QUIC
+/// datagrams.
+pub const FEATURE_UDP_DATAGRAM: u64 = 1;
+/// Udp streams start with a `UdpStream` instead of a `UdpFlow`, the flows of a route may share a
+/// multiplexed stream.
+pub const FEATURE_UDP_MULTIPLEX: u64 = 2;
+/// The client understands `Control::GrowStreams`.
+pub const FEATURE_GROW_STREAMS: u64 = 4;
+
+/// Exchanged first by both sides, its layout never changes. The server closes the connection
+/// with `CloseReason::VersionMismatch` if the versions are incompatible.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub features: u64,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: FEATURES,
+        }
+    }
+
+    pub fn compatible(&self, peer: &Hello) -> bool {
+        peer.version >= self.min_version && self.version >= peer.min_version
+    }
+
+    /// The features supported by both sides.
+    pub fn common_features(&self, peer: &Hello) -> u64 {
+        self.features & peer.features
+    }
+}
+
+/// Sent by the client after the `Hello`, answered with a `Challenge`.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Login {
+    /// Join the connection pool of an existing session instead of starting a new one.
+    pub session: Option<u64>,
+    /// Take over the session of a previous connection of this client, keeping its routes open.
+    ///
+    /// A new or resumed session is answered with its id right after `CODE_AUTH_SUCCESS`.
+    pub resume: Option<u64>,
+}
+
+/// A random nonce generated by the server for every handshake, so that an `Auth` can't be
+/// replayed on another connection.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Challenge {
+    pub nonce: Vec<u8>,
+}
+
+/// Proves that the client holds a token without sending it, see `Token::prove`, for
+/// `Login::signed_message`.
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct Auth {
-    pub token: String,
+    pub proof: Vec<u8>,
+}
+
+impl Login {
+    /// The nonce of the challenge followed by the login, so that neither can be altered.
+    pub fn signed_message(&self, nonce: &[u8]) -> Vec<u8> {
+        let mut msg = b"couscous auth".to_vec();
+        msg.extend_from_slice(nonce);
+        for id in [self.session, self.resume] {
+            match id {
+                Some(id) => {
+                    msg.push(1);
+                    msg.extend_from_slice(&id.to_le_bytes());
+                }
+                None => msg.push(0),
+            }
+        }
+        msg
+    }
 }
 
 #[derive(Encode, Decode, PartialEq, Debug)]
@@ -25,6 +236,26 @@ pub struct StreamStart {
     pub route_name: String,
 }
 
+/// Follows the `StreamStart` of a udp stream if both sides support `FEATURE_UDP_DATAGRAM`. The
+/// payloads of a flow with an id may be sent as datagrams prefixed with it in both directions,
+/// the others and the first one go over the stream.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct UdpFlow {
+    pub id: Option<u64>,
+}
+
+/// Follows the `StreamStart` of a udp stream if both sides support `FEATURE_UDP_MULTIPLEX`.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub enum UdpStream {
+    /// A stream carrying a single flow.
+    Flow(UdpFlow),
+    /// A stream carrying every flow of the route on this connection, as `udp::MuxFrame`s. Each
+    /// flow gets its own socket on the client, and a flow id the server hasn't used before opens
+    /// a new flow. With `datagram`, packets may also be sent as datagrams like a `UdpFlow` with an
+    /// id, except the first one of a flow.
+    Multiplexed { datagram: bool },
+}
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct RegisterRoute {
     pub name: String,
@@ -41,9 +272,127 @@ pub enum RegisterRouteRes {
 pub enum RegisterRouteError {
     Repeated(RegisterRoute),
     RouteNotFound(RegisterRoute),
+    /// The credentials of the client don't allow the route.
+    Forbidden(RegisterRoute),
     Other(String, RegisterRoute),
 }
 
+impl RegisterRouteError {
+    /// Whether registering the route again can't succeed until the configuration is changed.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            RegisterRouteError::RouteNotFound(_) | RegisterRouteError::Forbidden(_)
+        )
+    }
+}
+
+impl fmt::Display for RegisterRouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterRouteError::Repeated(route) => {
+                write!(
+                    f,
+                    "route `{}` has been registered on the server",
+                    route.name
+                )
+            }
+            RegisterRouteError::RouteNotFound(route) => {
+                write!(f, "route: `{}` does not exist on the server", route.name)
+            }
+            RegisterRouteError::Forbidden(route) => {
+                write!(f, "not allowed to register route `{}`", route.name)
+            }
+            RegisterRouteError::Other(err, route) => write!(
+                f,
+                "error: `{}` occurred while registering route `{}`",
+                err, route.name
+            ),
+        }
+    }
+}
+
+/// Messages exchanged over the control stream.
+///
+/// After the handshake, the first bi stream is kept open for the whole lifetime of the
+/// connection and both sides keep reading `Control` messages from it.
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub enum Control {
+    Ping(u64),
+    Pong(u64),
+    /// Register more routes at any time after the handshake.
+    RegisterRoute(Vec<RegisterRoute>),
+    /// The result of each route of a `RegisterRoute`, by route name. The routes sent during the
+    /// handshake are answered the same way.
+    RegisterRouteRes(Vec<(String, RegisterRouteRes)>),
+    /// Close registered routes, by route name.
+    UnregisterRoute(Vec<String>),
+    UnregisterRouteRes(Vec<(String, UnregisterRouteRes)>),
+    /// A registered route was closed by the server, e.g. after a configuration reload.
+    RouteClosed(String),
+    /// Ask the peer for its `Stats`, the client sends it with its pings when debug logging is on.
+    Stats,
+    StatsRes(Stats),
+    /// The peer is about to close the connection, with the reason.
+    Close(String),
+    /// Sent by the server while a tcp route waits for a stream, asks the client to allow this
+    /// many more concurrent bi streams on each of its connections until it stops asking.
+    GrowStreams(u32),
+}
+
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub enum UnregisterRouteRes {
+    Ok,
+    NotRegistered,
+}
+
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Stats {
+    pub routes: Vec<String>,
+    pub active_streams: u64,
+}
+
+pub type ControlSender = UnboundedSender<Control>;
+
+/// Control messages read ahead of the task handling them.
+const CONTROL_QUEUE_SIZE: usize = 16;
+
+/// Spawn a task that owns the sending half of the control stream, so that messages can be
+/// sent from anywhere through the returned channel.
+pub fn spawn_control_writer(mut stream: SendStream) -> ControlSender {
+    let (tx, mut rx) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let Err(err) = write_proto::<Control, 64>(&mut stream, msg).await {
+                log::debug!("control stream write error: {:?}", err);
+                break;
+            }
+        }
+        stream.finish().await.ok();
+    });
+    tx
+}
+
+/// Spawn a task that owns the receiving half of the control stream and decodes its messages.
+/// `read_proto` loses a partly read message when it is cancelled, so it is never raced in a
+/// `select!`, the returned channel is instead. The read error ends the messages.
+pub fn spawn_control_reader(mut stream: RecvStream) -> mpsc::Receiver<anyhow::Result<Control>> {
+    let (tx, rx) = mpsc::channel(CONTROL_QUEUE_SIZE);
+    tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = read_proto::<Control, 64>(&mut stream) => msg,
+                _ = tx.closed() => break,
+            };
+            let end = msg.is_err();
+            if tx.send(msg).await.is_err() || end {
+                break;
+            }
+        }
+    });
+    rx
+}
+
 #[inline]
 pub async fn read_proto<T, const C: usize>(stream: &mut RecvStream) -> anyhow::Result<T>
 where
@@ -101,3 +450,36 @@ pub trait VarIntWriter: AsyncWrite + Unpin {
 #[cfg(target_feature = "sse2")]
 #[async_trait::async_trait]
 impl<AW: AsyncWrite + Send + Unpin> VarIntWriter for AW {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_reason_codes() {
+        for reason in [
+            CloseReason::Shutdown,
+            CloseReason::AuthFailed,
+            CloseReason::SessionNotFound,
+            CloseReason::SessionTakenOver,
+            CloseReason::VersionMismatch,
+            CloseReason::Other(99),
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), reason);
+            assert_eq!(VarInt::from(reason).into_inner(), reason.code());
+        }
+        assert_eq!(CloseReason::from_code(11), CloseReason::Other(11));
+    }
+
+    #[test]
+    fn closed_from_error() {
+        let closed = Closed {
+            reason: CloseReason::VersionMismatch,
+            detail: String::new(),
+        };
+        let err = anyhow::Error::from(closed).context("handshake");
+        let found = Closed::from_error(&err).unwrap();
+        assert_eq!(found.reason, CloseReason::VersionMismatch);
+        assert!(Closed::from_error(&anyhow::anyhow!("other")).is_none());
+    }
+}
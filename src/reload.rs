@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::sync::watch;
+use tokio::time::Interval;
+
+use crate::config::{configuration, Config};
+
+/// Re-read the configuration file on SIGHUP, and whenever it or one of the `watched` files of
+/// the current configuration is modified if `reload_interval` is set. `select` picks the part of
+/// the new configuration that is published to the receiver.
+///
+/// The log level is applied here, a configuration that fails to load or to be selected is
+/// logged and ignored.
+pub fn spawn<T, F, W>(
+    path: PathBuf,
+    config: &Config,
+    initial: T,
+    select: F,
+    watched: W,
+) -> watch::Receiver<Arc<T>>
+where
+    T: Send + Sync + 'static,
+    F: Fn(Config) -> anyhow::Result<T> + Send + 'static,
+    W: Fn(&T) -> Vec<PathBuf> + Send + 'static,
+{
+    let mut files = Files::new(&path, watched(&initial));
+    let (tx, rx) = watch::channel(Arc::new(initial));
+    let mut interval = config
+        .reload_interval
+        .map(|t| tokio::time::interval(*t.duration()));
+    let mut log_level = config.log_level;
+
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    log::info!("SIGHUP received, reloading configuration");
+                }
+                _ = tick(&mut interval) => {
+                    match files.changed() {
+                        Some(file) => log::info!("{} changed, reloading", file.display()),
+                        None => continue,
+                    }
+                }
+                _ = tx.closed() => break,
+            }
+
+            let res: anyhow::Result<_> = try {
+                let config = configuration(path.clone())?;
+                (config.log_level, select(config)?)
+            };
+            match res {
+                Ok((level, config)) => {
+                    if level != log_level {
+                        log::set_max_level(level);
+                        log::info!("log level changed to {}", level);
+                        log_level = level;
+                    }
+                    files = Files::new(&path, watched(&config));
+                    tx.send(Arc::new(config)).ok();
+                }
+                Err(err) => log::error!("failed to reload configuration: {}", err),
+            }
+        }
+    });
+
+    rx
+}
+
+/// The watched files and their last modification time.
+struct Files(Vec<(PathBuf, Option<SystemTime>)>);
+
+impl Files {
+    fn new(config: &Path, watched: Vec<PathBuf>) -> Self {
+        Files(
+            std::iter::once(config.to_path_buf())
+                .chain(watched)
+                .map(|path| {
+                    let m = modified(&path);
+                    (path, m)
+                })
+                .collect(),
+        )
+    }
+
+    /// Update the modification times, returning the first file that changed.
+    fn changed(&mut self) -> Option<&Path> {
+        let mut changed = None;
+        for (i, (path, modified_at)) in self.0.iter_mut().enumerate() {
+            let m = modified(path);
+            if m != *modified_at {
+                *modified_at = m;
+                changed.get_or_insert(i);
+            }
+        }
+        changed.map(|i| self.0[i].0.as_path())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Hangup(signal(SignalKind::hangup()).ok())
+    }
+
+    async fn recv(&mut self) {
+        match &mut self.0 {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Hangup
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
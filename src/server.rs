@@ -1,67 +1,154 @@
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use fnv::FnvHashMap;
-use integer_encoding::VarIntAsyncReader;
-use quinn::{Connecting, Connection, ConnectionError, Endpoint, SendStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use parking_lot::Mutex;
+use quinn::{Connecting, ConnectionError, Endpoint, RecvStream, SendStream};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::JoinHandle;
 use tokio_graceful_shutdown::{SubsystemHandle, Toplevel};
 
-use crate::config::{RouteType, ServerConfig};
+use crate::auth::{authenticate, authenticate_cert, Identity, Lockouts};
+use crate::config::{RouteType, ServerConfig, ServerRoute, StreamAdmission};
+use crate::pool::{Backends, ConnPool, PooledConn, StreamGuard};
 use crate::proto::{
-    read_proto, write_proto, Auth, RegisterRoute, RegisterRouteError, RegisterRouteRes,
-    StreamStart, VarIntWriter, CODE_AUTH_FAILED, CODE_AUTH_SUCCESS, CODE_SHUTDOWN,
+    read_proto, spawn_control_reader, spawn_control_writer, write_proto, Auth, Challenge,
+    CloseReason, Control, ControlSender, Hello, Login, RegisterRoute, RegisterRouteError,
+    RegisterRouteRes, Stats, StreamStart, UdpFlow, UdpStream, UnregisterRouteRes, VarIntWriter,
+    CODE_AUTH_SUCCESS, FEATURE_GROW_STREAMS, FEATURE_UDP_DATAGRAM, FEATURE_UDP_MULTIPLEX,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::quic::{server_crypto, transport_config, QuicStream};
+use crate::udp::{
+    self, read_mux_frame, read_packet, write_mux_close, write_mux_packet, write_udp_stream,
+    Activity, FlowGuard, FlowSender, Flows, MuxFrame, SizeLimit,
 };
-use crate::quic::{transport_config, QuicStream};
 
-pub async fn run(config: Arc<ServerConfig>) -> anyhow::Result<()> {
-    let (ep, mut incoming) = Endpoint::server(
-        {
-            let mut c = quinn::ServerConfig::with_single_cert(
-                config.cert.clone(),
-                config.private_key.clone(),
-            )?;
-            c.transport = Arc::new(transport_config());
-            Arc::get_mut(&mut c.transport)
-                .unwrap()
-                .max_concurrent_bidi_streams(
-                    config.max_concurrent_bidi_streams.unwrap_or(100).into(),
-                );
-            c
-        },
-        config.bind,
-    )?;
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_UDP_MAX_FLOWS: usize = 1024;
+/// Packets queued for the stream of a udp flow, later ones are dropped until it catches up.
+const UDP_QUEUE_SIZE: usize = 64;
+const DEFAULT_STREAM_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `config` is watched for reloads, the listen address is only read at startup. The certificate,
+/// client CA and stream limit apply to new connections when they change.
+pub async fn run(config: watch::Receiver<Arc<ServerConfig>>) -> anyhow::Result<()> {
+    let initial = Arc::clone(&config.borrow());
+    let (ep, mut incoming) = Endpoint::server(endpoint_config(&initial)?, initial.bind)?;
+    tokio::spawn(reload_endpoint(ep.clone(), config.clone()));
 
     log::info!("listen on {}", ep.local_addr()?);
 
+    let sessions = Sessions::default();
+    let routes = SharedRoutes::default();
+    let lockouts = Arc::new(Lockouts::default());
+
     while let Some(connection) = incoming.next().await {
-        let config = Arc::clone(&config);
+        let remote_addr = connection.remote_address();
+        if lockouts.is_banned(remote_addr.ip()) {
+            log::debug!("rejected connection from banned address {}", remote_addr);
+            continue;
+        }
+
+        let config = config.clone();
+        let sessions = Arc::clone(&sessions);
+        let routes = Arc::clone(&routes);
+        let lockouts = Arc::clone(&lockouts);
         tokio::spawn(async move {
-            Toplevel::new()
-                .start("handle connecting", |h| handle_conn(connection, config, h))
+            let res = Toplevel::new()
+                .start("handle connecting", |h| {
+                    handle_conn(connection, config, sessions, routes, lockouts, h)
+                })
                 .handle_shutdown_requests(Duration::from_secs(3))
-                .await
-                .unwrap();
+                .await;
+            if let Err(err) = res {
+                log::debug!(
+                    "connection from {} ended with an error: {:#}",
+                    remote_addr,
+                    err
+                );
+            }
         });
     }
 
     tokio::time::timeout(Duration::from_secs(5), ep.wait_idle())
         .await
         .unwrap();
-    ep.close(CODE_SHUTDOWN.into(), &[]);
+    ep.close(CloseReason::Shutdown.into(), b"server shutdown");
     log::info!("server closed");
 
     Ok(())
 }
 
+fn endpoint_config(config: &ServerConfig) -> anyhow::Result<quinn::ServerConfig> {
+    let mut c = quinn::ServerConfig::with_crypto(Arc::new(server_crypto(config)?));
+    let mut transport = transport_config();
+    transport.max_concurrent_bidi_streams(config.max_concurrent_bidi_streams.unwrap_or(100).into());
+    c.transport = Arc::new(transport);
+    Ok(c)
+}
+
+/// Swap the endpoint configuration when the certificate, client CA or stream limit changes.
+/// Established connections keep their TLS session and are not affected.
+async fn reload_endpoint(ep: Endpoint, mut config: watch::Receiver<Arc<ServerConfig>>) {
+    let mut current = Arc::clone(&config.borrow());
+    while config.changed().await.is_ok() {
+        let new = Arc::clone(&config.borrow());
+        if new.cert == current.cert
+            && new.private_key == current.private_key
+            && new.client_auth == current.client_auth
+            && new.max_concurrent_bidi_streams == current.max_concurrent_bidi_streams
+        {
+            continue;
+        }
+        match endpoint_config(&new) {
+            Ok(c) => {
+                ep.set_server_config(Some(c));
+                log::info!("certificate reloaded, applies to new connections");
+                current = new;
+            }
+            Err(err) => log::error!(
+                "failed to reload certificate, keep the current one: {}",
+                err
+            ),
+        }
+    }
+}
+
+/// The authenticated clients, by session id.
+type Sessions = Arc<Mutex<FnvHashMap<u64, Arc<Session>>>>;
+
+/// A client session owns the connection pool and the routes of a client, it can be taken over
+/// by a new connection of the same client without closing the routes.
+struct Session {
+    id: u64,
+    identity: Identity,
+    pool: Arc<ConnPool>,
+    /// The routes of every session, shared with the other clients serving the same routes.
+    shared: SharedRoutes,
+    /// Held by the control loop of the owner connection.
+    routes: tokio::sync::Mutex<FnvHashMap<String, RouteHandle>>,
+    /// `stable_id` of the connection serving the control stream.
+    owner: AtomicUsize,
+    /// The features of the owner connection.
+    features: AtomicU64,
+    taken_over: Notify,
+}
+
 async fn handle_conn(
     connection: Connecting,
-    config: Arc<ServerConfig>,
+    config: watch::Receiver<Arc<ServerConfig>>,
+    sessions: Sessions,
+    shared: SharedRoutes,
+    lockouts: Arc<Lockouts>,
     sys_handle: SubsystemHandle,
 ) -> anyhow::Result<()> {
+    let current = Arc::clone(&config.borrow());
     let remote_addr = connection.remote_address();
     log::info!("client {} connecting", remote_addr);
     let mut new_conn = connection.await?;
@@ -71,127 +158,592 @@ async fn handle_conn(
         .await
         .ok_or_else(|| anyhow::anyhow!("handshake_stream is missing"))??;
 
-    // authorization
-    let auth = read_proto::<Auth, 32>(&mut handshake_stream.1).await?;
-    if auth.token != config.token {
-        log::error!("client {} authorization failed", remote_addr);
-        new_conn.connection.close(CODE_AUTH_FAILED.into(), &[]);
+    // version negotiation
+    let hello = read_proto::<Hello, 16>(&mut handshake_stream.1).await?;
+    let current_hello = Hello::current();
+    write_proto::<_, 16>(&mut handshake_stream.0, current_hello.clone()).await?;
+    if !current_hello.compatible(&hello) {
+        log::error!(
+            "client {} protocol version {} is incompatible, {} to {} are supported",
+            remote_addr,
+            hello.version,
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+        CloseReason::VersionMismatch.close(
+            &new_conn.connection,
+            &format!(
+                "versions {} to {} are supported",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        );
         return Ok(());
-    } else {
-        log::debug!("authentication success");
-        handshake_stream.0.write_u8(CODE_AUTH_SUCCESS).await?;
     }
+    let features = current_hello.common_features(&hello);
+    log::debug!(
+        "client {} protocol version {}, features {:#x}",
+        remote_addr,
+        hello.version,
+        features
+    );
+
+    // authorization
+    let login = read_proto::<Login, 32>(&mut handshake_stream.1).await?;
+    let nonce = ring::rand::generate::<[u8; 32]>(&ring::rand::SystemRandom::new())
+        .map_err(|_| anyhow::anyhow!("failed to generate nonce"))?
+        .expose();
+    write_proto::<_, 64>(
+        &mut handshake_stream.0,
+        Challenge {
+            nonce: nonce.to_vec(),
+        },
+    )
+    .await?;
+    let auth = read_proto::<Auth, 64>(&mut handshake_stream.1).await?;
+    let certs = new_conn
+        .connection
+        .peer_identity()
+        .and_then(|i| i.downcast::<Vec<rustls::Certificate>>().ok());
+    let identity = match certs.map_or_else(
+        || authenticate(&current, &login.signed_message(&nonce), &auth.proof),
+        |certs| authenticate_cert(&current, &certs),
+    ) {
+        Some(identity) => identity,
+        None => {
+            log::error!("client {} authorization failed", remote_addr);
+            lockouts.fail(remote_addr.ip(), &current.lockout);
+            CloseReason::AuthFailed.close(&new_conn.connection, "");
+            return Ok(());
+        }
+    };
+    lockouts.succeed(remote_addr.ip());
 
     let conn = Arc::new(new_conn.connection);
+    let flows = udp::spawn_flows(features, new_conn.datagrams);
 
+    // everything of the connection ends with it, whatever the reason it is closed for
     let handle = sys_handle.clone();
     tokio::spawn(async move {
-        if let Some(Err(ConnectionError::TimedOut)) = new_conn.uni_streams.next().await {
-            log::info!("client `{}` timeout", remote_addr);
-            handle.request_shutdown()
+        loop {
+            match new_conn.uni_streams.next().await {
+                Some(Ok(_)) => continue,
+                Some(Err(ConnectionError::TimedOut)) => {
+                    log::info!("client `{}` timeout", remote_addr)
+                }
+                Some(Err(err)) => log::info!("client `{}` disconnected: {}", remote_addr, err),
+                None => log::info!("client `{}` disconnected", remote_addr),
+            }
+            break;
         }
+        handle.request_shutdown()
     });
 
+    // an additional data connection of an existing session
+    if let Some(session) = login.session {
+        let pool = match sessions
+            .lock()
+            .get(&session)
+            .filter(|s| s.identity == identity)
+        {
+            Some(s) => Arc::clone(&s.pool),
+            None => {
+                log::error!("client {} joins an unknown session", remote_addr);
+                CloseReason::SessionNotFound.close(&conn, "");
+                return Ok(());
+            }
+        };
+        log::debug!("authentication success");
+        handshake_stream.0.write_u8(CODE_AUTH_SUCCESS).await?;
+        handshake_stream.0.finish().await?;
+
+        pool.insert(Arc::clone(&conn), flows);
+        log::info!("client {} joined session {:x}", remote_addr, session);
+        sys_handle.on_shutdown_requested().await;
+        pool.remove(&conn);
+        return Ok(());
+    }
+
+    log::debug!("authentication success as {}", identity);
+    let owner = conn.stable_id();
+    let resumed = login.resume.and_then(|id| {
+        let sessions = sessions.lock();
+        let session = sessions.get(&id).filter(|s| s.identity == identity)?;
+        session.owner.store(owner, Ordering::SeqCst);
+        session.features.store(features, Ordering::SeqCst);
+        session.taken_over.notify_one();
+        Some((id, Arc::clone(session)))
+    });
+    let (id, session) = match resumed {
+        Some((id, session)) => {
+            log::info!("client {} resumes session {:x}", remote_addr, id);
+            session
+                .pool
+                .take_over(Arc::clone(&conn), flows, CloseReason::SessionTakenOver);
+            (id, session)
+        }
+        None => {
+            let id = new_session_id()?;
+            let pool = Arc::new(ConnPool::new(current.pool_strategy));
+            pool.insert(Arc::clone(&conn), flows);
+            let session = Arc::new(Session {
+                id,
+                identity,
+                pool,
+                shared,
+                routes: Default::default(),
+                owner: AtomicUsize::new(owner),
+                features: AtomicU64::new(features),
+                taken_over: Notify::new(),
+            });
+            sessions.lock().insert(id, Arc::clone(&session));
+            (id, session)
+        }
+    };
+
+    // the session is owned by this connection now, every exit goes through the cleanup below
+    let res: anyhow::Result<()> = try {
+        handshake_stream.0.write_u8(CODE_AUTH_SUCCESS).await?;
+        write_proto::<_, 8>(&mut handshake_stream.0, id).await?;
+
+        // waits for the control loop of a taken over connection to give the routes up
+        let mut routes = session.routes.lock().await;
+        let res = serve_session(
+            config,
+            handshake_stream,
+            &session,
+            owner,
+            &mut routes,
+            &sys_handle,
+            remote_addr,
+        )
+        .await;
+        session.pool.set_control(None);
+        drop(routes);
+        res?
+    };
+
+    if session.owner.load(Ordering::SeqCst) != owner {
+        log::info!("session {:x} taken over", id);
+    } else if let Some(timeout) = current.resume_timeout {
+        log::info!("session {:x} can be resumed in {}", id, timeout);
+        tokio::spawn(async move {
+            tokio::time::sleep(*timeout.duration()).await;
+            close_session(&sessions, id, &session, owner).await;
+        });
+    } else {
+        close_session(&sessions, id, &session, owner).await;
+    }
+    res
+}
+
+/// Close a session unless it was taken over since `owner` lost it, releasing the listeners so
+/// that the client can register the same routes again as soon as it reconnects.
+async fn close_session(sessions: &Sessions, id: u64, session: &Session, owner: usize) {
+    {
+        let mut sessions = sessions.lock();
+        if session.owner.load(Ordering::SeqCst) != owner {
+            return;
+        }
+        sessions.remove(&id);
+    }
+
+    for (_, route) in session.routes.lock().await.drain() {
+        route.close().await;
+    }
+    session.pool.close(CloseReason::Shutdown);
+    log::info!("session {:x} closed", id);
+}
+
+/// Register the routes of a session, then serve its control stream until the connection is
+/// closed or taken over.
+async fn serve_session(
+    mut config: watch::Receiver<Arc<ServerConfig>>,
+    mut handshake_stream: (SendStream, RecvStream),
+    session: &Session,
+    owner: usize,
+    routes: &mut FnvHashMap<String, RouteHandle>,
+    sys_handle: &SubsystemHandle,
+    remote_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let mut current = Arc::clone(&config.borrow());
+
     // exchange routing information
-    let routes = read_proto::<Vec<RegisterRoute>, 1024>(&mut handshake_stream.1).await?;
+    let register = read_proto::<Vec<RegisterRoute>, 1024>(&mut handshake_stream.1).await?;
 
-    #[allow(clippy::never_loop)]
-    'a: loop {
-        for route in routes {
-            if let res @ RegisterRouteRes::Err(_) =
-                register_route(&config, route, &conn, sys_handle.clone()).await
-            {
-                write_proto::<_, 32>(&mut handshake_stream.0, res).await?;
-                break 'a;
-            }
+    // the routes of a resumed session that are not registered again are closed
+    let unwanted = routes
+        .keys()
+        .filter(|name| register.iter().all(|r| &r.name != *name))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in unwanted {
+        if let Some(route) = routes.remove(&name) {
+            route.close().await;
         }
-        write_proto::<_, 4>(&mut handshake_stream.0, RegisterRouteRes::Ok).await?;
-        break;
     }
 
-    handshake_stream.0.finish().await?;
+    let res = register_routes(&current, register, session, routes).await;
+    write_proto::<_, 256>(&mut handshake_stream.0, res).await?;
+
+    // the handshake stream becomes the control stream
+    let (send_stream, recv_stream) = handshake_stream;
+    let control = spawn_control_writer(send_stream);
+    let mut messages = spawn_control_reader(recv_stream);
+    if session.features.load(Ordering::SeqCst) & FEATURE_GROW_STREAMS != 0 {
+        session.pool.set_control(Some(control.clone()));
+    }
+
+    loop {
+        tokio::select! {
+            msg = messages.recv() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => {
+                        log::debug!("control stream of {} closed: {:#}", remote_addr, err);
+                        break;
+                    }
+                    None => break,
+                };
+                match msg {
+                    Control::Ping(n) => {
+                        control.send(Control::Pong(n)).ok();
+                    }
+                    Control::Pong(_) => {}
+                    Control::RegisterRoute(register) => {
+                        let res = register_routes(&current, register, session, routes).await;
+                        control.send(Control::RegisterRouteRes(res)).ok();
+                    }
+                    Control::UnregisterRoute(names) => {
+                        let mut res = Vec::with_capacity(names.len());
+                        for name in names {
+                            let r = match routes.remove(&name) {
+                                Some(route) => {
+                                    route.close().await;
+                                    log::info!("route {} unregistered", &name);
+                                    UnregisterRouteRes::Ok
+                                }
+                                None => UnregisterRouteRes::NotRegistered,
+                            };
+                            res.push((name, r));
+                        }
+                        control.send(Control::UnregisterRouteRes(res)).ok();
+                    }
+                    Control::Stats => {
+                        control
+                            .send(Control::StatsRes(Stats {
+                                routes: routes.keys().cloned().collect(),
+                                active_streams: session.pool.active_streams(),
+                            }))
+                            .ok();
+                    }
+                    Control::Close(reason) => {
+                        log::info!("client {} closing: {}", remote_addr, reason);
+                        break;
+                    }
+                    msg => log::warn!("unexpected control message from {}: {:?}", remote_addr, msg),
+                }
+            }
+            Ok(()) = config.changed() => {
+                let new = Arc::clone(&config.borrow());
+                reload_routes(&current, &new, session, routes, &control).await;
+                current = new;
+            }
+            _ = session.taken_over.notified() => {
+                if session.owner.load(Ordering::SeqCst) != owner {
+                    log::info!("client {} taken over by a new connection", remote_addr);
+                    break;
+                }
+            }
+            _ = sys_handle.on_shutdown_requested() => {
+                control.send(Control::Close(String::from("server shutdown"))).ok();
+                break;
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Apply a reloaded configuration to the routes of a session: routes whose settings changed are
+/// rebuilt, routes that can no longer be registered are closed, the others are left untouched.
+async fn reload_routes(
+    old: &ServerConfig,
+    new: &ServerConfig,
+    session: &Session,
+    routes: &mut FnvHashMap<String, RouteHandle>,
+    control: &ControlSender,
+) {
+    let names = routes.keys().cloned().collect::<Vec<_>>();
+    for name in names {
+        let (old_route, new_route) = (old.route.get(&*name), new.route.get(&*name));
+        let _type = match old_route {
+            Some(r) if old_route != new_route || !session.identity.may_register(new, &name) => {
+                r._type
+            }
+            _ => continue,
+        };
+
+        if let Some(route) = routes.remove(&name) {
+            route.close().await;
+        }
+        let register = RegisterRoute {
+            name: name.clone(),
+            _type,
+        };
+        match register_route(new, register, session, routes).await {
+            RegisterRouteRes::Ok => log::info!("route {} reloaded", name),
+            RegisterRouteRes::Err(err) => {
+                log::warn!("route {} closed by reload: {:?}", name, err);
+                control.send(Control::RouteClosed(name)).ok();
+            }
+        }
+    }
+}
+
+fn new_session_id() -> anyhow::Result<u64> {
+    let bytes = ring::rand::generate::<[u8; 8]>(&ring::rand::SystemRandom::new())
+        .map_err(|_| anyhow::anyhow!("failed to generate session id"))?
+        .expose();
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The routes registered by every session, by name.
+type SharedRoutes = Arc<tokio::sync::Mutex<FnvHashMap<String, SharedRoute>>>;
+
+/// A route listener and the clients it balances its connections over.
+struct SharedRoute {
+    config: ServerRoute,
+    backends: Arc<Backends>,
+    listener: Listener,
+}
+
+/// A route listener, closed when the handle is closed or dropped.
+struct Listener {
+    close: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Listener {
+    /// Close the listener and wait for it to be released.
+    async fn close(self) {
+        self.close.send(()).ok();
+        self.task.await.ok();
+    }
+}
+
+/// A route registered by a session, the listener is closed with the last session serving it.
+struct RouteHandle {
+    name: String,
+    session: u64,
+    shared: SharedRoutes,
+}
+
+impl RouteHandle {
+    /// Stop serving the route, closing its listener if no other client serves it.
+    async fn close(self) {
+        let route = {
+            let mut shared = self.shared.lock().await;
+            match shared.get(&self.name) {
+                Some(route) if !route.backends.remove(self.session) => shared.remove(&self.name),
+                _ => None,
+            }
+        };
+        // closing waits for the listener task, never hold the lock of every route meanwhile
+        if let Some(route) = route {
+            route.listener.close().await;
+        }
+    }
+}
+
+/// Register each route independently, returning the result of every route by name.
+async fn register_routes(
+    config: &ServerConfig,
+    register: Vec<RegisterRoute>,
+    session: &Session,
+    routes: &mut FnvHashMap<String, RouteHandle>,
+) -> Vec<(String, RegisterRouteRes)> {
+    let mut res = Vec::with_capacity(register.len());
+    for route in register {
+        let name = route.name.clone();
+        res.push((name, register_route(config, route, session, routes).await));
+    }
+    res
+}
+
+/// Add the session to the clients serving a route, the route listener is built by the first
+/// session registering it.
 async fn register_route(
     config: &ServerConfig,
     register_route: RegisterRoute,
-    conn: &Arc<Connection>,
-    sys_handle: SubsystemHandle,
+    session: &Session,
+    routes: &mut FnvHashMap<String, RouteHandle>,
 ) -> RegisterRouteRes {
-    if let Some(r) = config
+    // already registered by this session, e.g. before it was resumed
+    if routes.contains_key(&register_route.name) {
+        return RegisterRouteRes::Ok;
+    }
+
+    let r = match config
         .route
         .get(&*register_route.name)
         .filter(|r| r._type == register_route._type)
     {
-        let conn = Arc::clone(conn);
-        if let Err(err) = match register_route._type {
+        Some(r) => r,
+        None => return RegisterRouteRes::Err(RegisterRouteError::RouteNotFound(register_route)),
+    };
+    if !session.identity.may_register(config, &register_route.name) {
+        log::warn!(
+            "client {} is not allowed to register route {}",
+            session.identity,
+            register_route.name
+        );
+        return RegisterRouteRes::Err(RegisterRouteError::Forbidden(register_route));
+    }
+
+    // clients of a listener whose settings were reloaded, it is rebuilt for them
+    let mut carried: Option<Backends> = None;
+    let backends = loop {
+        let mut shared = session.shared.lock().await;
+        match shared.get(&register_route.name) {
+            Some(route) if route.config == *r => {
+                let backends = Arc::clone(&route.backends);
+                if let Some(carried) = &carried {
+                    backends.extend(carried);
+                }
+                break backends;
+            }
+            Some(_) => {
+                let old = shared.remove(&register_route.name).unwrap();
+                drop(shared);
+                // the old listener must be closed to free its address, without holding the lock
+                // of every route while its task ends
+                old.listener.close().await;
+                carried
+                    .get_or_insert_with(|| Backends::new(r.balance))
+                    .extend(&old.backends);
+                continue;
+            }
+            None => {}
+        }
+
+        // binding doesn't wait on other tasks, the lock keeps another session from racing it
+        let backends = Arc::new(Backends::new(r.balance));
+        if let Some(carried) = &carried {
+            backends.extend(carried);
+        }
+        let listener = match register_route._type {
             RouteType::Tcp => {
-                build_tcp_route(
-                    conn,
-                    r.bind,
-                    register_route.name.clone(),
-                    sys_handle.clone(),
-                )
-                .await
+                build_tcp_route(Arc::clone(&backends), r, register_route.name.clone()).await
             }
             RouteType::Udp => {
-                build_udp_route(
-                    conn,
-                    r.bind,
-                    register_route.name.clone(),
-                    sys_handle.clone(),
-                    r.udp_buffer.unwrap_or(2048),
-                )
-                .await
+                build_udp_route(Arc::clone(&backends), r, register_route.name.clone()).await
             }
-        } {
-            if matches!(err.kind(), std::io::ErrorKind::AddrInUse) {
-                RegisterRouteRes::Err(RegisterRouteError::Repeated(register_route))
-            } else {
-                RegisterRouteRes::Err(RegisterRouteError::Other(err.to_string(), register_route))
+        };
+        match listener {
+            Err(err) if matches!(err.kind(), std::io::ErrorKind::AddrInUse) => {
+                return RegisterRouteRes::Err(RegisterRouteError::Repeated(register_route));
+            }
+            Err(err) => {
+                return RegisterRouteRes::Err(RegisterRouteError::Other(
+                    err.to_string(),
+                    register_route,
+                ));
+            }
+            Ok(listener) => {
+                shared.insert(
+                    register_route.name.clone(),
+                    SharedRoute {
+                        config: r.clone(),
+                        backends: Arc::clone(&backends),
+                        listener,
+                    },
+                );
             }
-        } else {
-            log::info!(
-                "route {}({:?}) registered",
-                &register_route.name,
-                register_route._type
-            );
-            RegisterRouteRes::Ok
         }
-    } else {
-        RegisterRouteRes::Err(RegisterRouteError::RouteNotFound(register_route))
-    }
+        break backends;
+    };
+
+    backends.insert(session.id, Arc::clone(&session.pool));
+    log::info!(
+        "route {}({:?}) registered",
+        &register_route.name,
+        register_route._type
+    );
+    routes.insert(
+        register_route.name.clone(),
+        RouteHandle {
+            name: register_route.name,
+            session: session.id,
+            shared: Arc::clone(&session.shared),
+        },
+    );
+    RegisterRouteRes::Ok
 }
 
 async fn build_tcp_route(
-    conn: Arc<Connection>,
-    addr: SocketAddr,
+    backends: Arc<Backends>,
+    route: &ServerRoute,
     route_name: String,
-    sys_handle: SubsystemHandle,
-) -> std::io::Result<()> {
+) -> std::io::Result<Listener> {
+    let addr = route.bind;
+    let admission = route.stream_admission;
+    let queue_timeout = route
+        .stream_queue_timeout
+        .map_or(DEFAULT_STREAM_QUEUE_TIMEOUT, |t| *t.duration());
+    let rejected = Arc::new(AtomicU64::new(0));
     let listener = TcpListener::bind(addr).await?;
-    tokio::spawn(async move {
+    let (close_tx, mut close) = oneshot::channel();
+    let task = tokio::spawn(async move {
         log::info!("tcp route listen on {}", addr);
 
         loop {
             tokio::select! {
                 biased;
 
+                _ = &mut close => {
+                    break
+                }
+
                 Ok((tcp_stream, _addr)) = listener.accept() => {
-                    let conn = conn.clone();
+                    let backends = Arc::clone(&backends);
+                    let rejected = Arc::clone(&rejected);
                     let route_name = route_name.clone();
                     tokio::spawn(async move {
-                        let (mut send_stream, recv_stream) = conn.open_bi().await.unwrap();
-                        write_proto::<_, 32>(
-                            &mut send_stream,
-                            StreamStart {
-                                route_name: route_name.clone(),
-                            },
-                        )
-                        .await
-                        .unwrap();
+                        let admitted = match backends.pick() {
+                            Some(_) => admit(&backends, admission, queue_timeout).await,
+                            None => {
+                                log::warn!("no client available for route `{}`", route_name);
+                                None
+                            }
+                        };
+                        let (_conn, mut send_stream, recv_stream, _guard) =
+                            match admitted {
+                                Some(s) => s,
+                                None => {
+                                    let count = rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                                    log::warn!(
+                                        "tcp stream `{}` rejected, no stream available, {} so far \
+                                         (route: `{}` tcp)",
+                                        _addr,
+                                        count,
+                                        route_name
+                                    );
+                                    // dropped with a zero linger, the peer gets a RST
+                                    tcp_stream.set_linger(Some(Duration::ZERO)).ok();
+                                    return;
+                                }
+                            };
+                        let start = StreamStart {
+                            route_name: route_name.clone(),
+                        };
+                        if let Err(err) = write_proto::<_, 32>(&mut send_stream, start).await {
+                            log::debug!(
+                                "failed to start stream: {} (route: `{}` tcp)",
+                                err,
+                                route_name
+                            );
+                            return;
+                        }
 
                         let mut quic_stream = tokio::io::BufStream::new(QuicStream {
                             bi: (send_stream, recv_stream),
@@ -203,104 +755,544 @@ async fn build_tcp_route(
                         log::info!("tcp stream `{}` disconnect. (route: `{}` tcp)", _addr, route_name);
                     });
                 }
-                _ = sys_handle.on_shutdown_requested() => {
-                    break
-                }
             }
         }
         log::info!("tcp route `{}` close", addr);
     });
 
-    Ok(())
+    Ok(Listener {
+        close: close_tx,
+        task,
+    })
+}
+
+/// Open a stream for a tcp connection of a route once one is available, according to the
+/// route's `stream_admission`. `None` if the connection is rejected.
+async fn admit(
+    backends: &Backends,
+    admission: StreamAdmission,
+    queue_timeout: Duration,
+) -> Option<(PooledConn, SendStream, RecvStream, StreamGuard)> {
+    if let Some(stream) = backends.try_open_bi() {
+        return Some(stream);
+    }
+    match admission {
+        StreamAdmission::Reject => return None,
+        // only the client whose connection is waited on is asked for one more stream
+        StreamAdmission::Grow => match backends.grow_streams(1) {
+            Some(conn) => {
+                let open = tokio::time::timeout(queue_timeout, conn.open_bi());
+                let (send_stream, recv_stream, guard) = open.await.ok()?.ok()?;
+                return Some((conn, send_stream, recv_stream, guard));
+            }
+            None => log::debug!("no client of the route can grow its stream limit"),
+        },
+        StreamAdmission::Queue => {}
+    }
+    tokio::time::timeout(queue_timeout, backends.open_bi())
+        .await
+        .ok()
+        .flatten()
 }
 
 async fn build_udp_route(
-    conn: Arc<Connection>,
-    addr: SocketAddr,
+    backends: Arc<Backends>,
+    route: &ServerRoute,
     route_name: String,
-    sys_handle: SubsystemHandle,
-    udp_buffer_size: usize,
-) -> std::io::Result<()> {
+) -> std::io::Result<Listener> {
+    let addr = route.bind;
+    let idle_timeout = route
+        .udp_idle_timeout
+        .map_or(DEFAULT_UDP_IDLE_TIMEOUT, |t| *t.duration());
+    let max_flows = route.udp_max_flows.unwrap_or(DEFAULT_UDP_MAX_FLOWS);
     let socket = Arc::new(UdpSocket::bind(addr).await?);
-    tokio::spawn(async move {
+    let (ended_tx, mut ended) = mpsc::channel(5);
+    let mut udp = UdpRoute {
+        shared: Arc::new(UdpShared {
+            limit: SizeLimit::new(route.udp_buffer, route.udp_oversize, route_name.clone()),
+            name: route_name,
+            socket: Arc::clone(&socket),
+            backends,
+            datagram: route.udp_datagram.unwrap_or(false),
+            ended: ended_tx,
+        }),
+        multiplex: route.udp_multiplex.unwrap_or(false),
+        flows: FnvHashMap::default(),
+        muxes: FnvHashMap::default(),
+        next_seq: 0,
+    };
+    let (close_tx, mut close) = oneshot::channel();
+    let task = tokio::spawn(async move {
         log::info!("udp route listen on {}", addr);
 
-        let mut socket_streams = FnvHashMap::<_, SendStream>::default();
-        let (tx, mut rx) = tokio::sync::mpsc::channel(5);
-
-        let mut buf = BytesMut::with_capacity(udp_buffer_size);
-        buf.resize(udp_buffer_size, 0);
+        let mut evict = tokio::time::interval((idle_timeout / 2).max(Duration::from_secs(1)));
+        let mut buf = BytesMut::with_capacity(udp.shared.limit.recv_buffer_size());
 
+        // every arm returns without waiting on a stream, so that closing the route is never
+        // held up by a client
         loop {
+            buf.resize(udp.shared.limit.recv_buffer_size(), 0);
             tokio::select! {
                 biased;
 
-                Some(addr) = rx.recv() => {
-                    socket_streams.remove(&addr);
+                _ = &mut close => {
+                    break
                 }
 
-                Ok((len, addr)) = socket.recv_from(&mut buf) => {
-                    if let Some(send_stream) = socket_streams.get_mut(&addr) {
-                        if let anyhow::Result::<_>::Err(_) = try {
-                            send_stream.write_varint(len as u32).await?;
-                            send_stream.write_all(buf.copy_to_bytes(len).as_ref()).await?;
-                        } {
-                            socket_streams.remove(&addr);
-                        }
+                Some(ended) = ended.recv() => udp.ended(ended),
+
+                _ = evict.tick() => udp.evict(idle_timeout),
+
+                Ok((len, peer)) = socket.recv_from(&mut buf) => {
+                    if !udp.shared.limit.check(len, peer) {
+                        continue
+                    }
+                    let packet = buf.copy_to_bytes(len);
+                    if udp.flows.contains_key(&peer) {
+                        udp.send(peer, packet);
+                    } else if udp.flows.len() >= max_flows {
+                        log::debug!(
+                            "too many udp flows, packet from {} dropped (route: `{}` udp)",
+                            peer,
+                            udp.shared.name
+                        );
                     } else {
-                        let (send_stream, recv_stream) = match try {
-                            let (mut send_stream, recv_stream) = conn.open_bi().await?;
-                            write_proto::<_, 32>(
-                                &mut send_stream,
-                                StreamStart {
-                                    route_name: route_name.clone(),
-                                },
-                            )
-                            .await?;
-                            send_stream.write_varint(len as u32).await?;
-                            send_stream.write_all(buf.copy_to_bytes(len).as_ref()).await?;
-                            (send_stream, recv_stream)
-                        } {
-                            anyhow::Result::<_>::Err(err) => {
-                                log::debug!("error sending data for the first time: {:?}", err);
-                                continue
-                            }
-                            Ok(o) => o,
-                        };
-                        socket_streams.insert(addr, send_stream);
-
-                        let socket = Arc::clone(&socket);
-                        let conn = Arc::clone(&conn);
-                        let tx = tx.clone();
-                        let route_name = route_name.clone();
-                        tokio::spawn(async move {
-                            let mut buf = BytesMut::with_capacity(udp_buffer_size);
-                            let mut buf_reader = tokio::io::BufReader::new(recv_stream);
-                            loop {
-                                if let anyhow::Result::<_>::Err(_) = try {
-                                    let len = buf_reader.read_varint_async().await?;
-                                    buf.resize(len, 0);
-                                    buf_reader.read_exact(&mut buf).await?;
-                                    socket.send_to(&buf.copy_to_bytes(len), addr).await?;
-                                } {
-                                    tx.send(addr).await.ok();
-                                    log::info!("udp data stream `{}` disconnect. (route: `{}` udp)", conn.remote_address(), route_name);
-                                    break
-                                }
-                            }
-                        });
+                        udp.open_flow(peer, packet);
                     }
-                    buf.resize(udp_buffer_size, 0);
                 }
+            }
+        }
 
-                _ = sys_handle.on_shutdown_requested() => {
-                    break
+        log::info!("udp route {} close", addr);
+    });
+
+    Ok(Listener {
+        close: close_tx,
+        task,
+    })
+}
+
+/// The state of a udp route listener, owned by its task.
+struct UdpRoute {
+    shared: Arc<UdpShared>,
+    multiplex: bool,
+    /// By the address of the peer.
+    flows: FnvHashMap<SocketAddr, RouteFlow>,
+    /// The multiplexed stream of each client connection, by `stable_id`.
+    muxes: FnvHashMap<usize, Mux>,
+    next_seq: u64,
+}
+
+/// What the tasks writing and reading the streams of a udp route share with it.
+struct UdpShared {
+    name: String,
+    socket: Arc<UdpSocket>,
+    backends: Arc<Backends>,
+    limit: SizeLimit,
+    datagram: bool,
+    ended: mpsc::Sender<Ended>,
+}
+
+/// A udp flow of a route.
+struct RouteFlow {
+    /// Tells the flow apart from a later one of the same peer.
+    seq: u64,
+    activity: Activity,
+    sender: RouteFlowSender,
+}
+
+enum RouteFlowSender {
+    /// A stream of its own, written by its task. The stream is finished once this is dropped.
+    Stream(mpsc::Sender<Bytes>),
+    /// A flow of the multiplexed stream of a connection.
+    Mux {
+        conn: usize,
+        id: u64,
+        _guard: FlowGuard,
+    },
+}
+
+/// The multiplexed stream of a route on a client connection, written by its task. The stream is
+/// finished once this is dropped.
+struct Mux {
+    seq: u64,
+    packets: mpsc::Sender<(u64, Bytes)>,
+    /// Flows opened and closed, never dropped unlike packets.
+    control: mpsc::UnboundedSender<MuxControl>,
+}
+
+enum MuxControl {
+    /// The first packet of a flow, it opens the flow on the client.
+    Open(u64, Bytes),
+    Close(u64),
+}
+
+/// Sent by the tasks of a flow or of a multiplexed stream once its stream ends.
+enum Ended {
+    Flow(SocketAddr, u64),
+    Mux(usize, u64),
+}
+
+impl UdpRoute {
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    fn ended(&mut self, ended: Ended) {
+        match ended {
+            Ended::Flow(peer, seq) => {
+                if self.flows.get(&peer).map_or(false, |flow| flow.seq == seq) {
+                    self.flows.remove(&peer);
+                }
+            }
+            Ended::Mux(conn, seq) => {
+                if self.muxes.get(&conn).map_or(false, |mux| mux.seq == seq) {
+                    self.close_mux(conn);
                 }
             }
         }
+    }
 
-        log::info!("udp route {} close", addr);
+    /// Forget a multiplexed stream and every flow it carries.
+    fn close_mux(&mut self, conn_id: usize) {
+        self.muxes.remove(&conn_id);
+        self.flows.retain(
+            |_, flow| !matches!(flow.sender, RouteFlowSender::Mux { conn, .. } if conn == conn_id),
+        );
+    }
+
+    /// Close the flows without packets for `idle_timeout`. A flow of its own is closed by
+    /// finishing its stream, a multiplexed one by a close frame, the client then closes its socket.
+    fn evict(&mut self, idle_timeout: Duration) {
+        let idle = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| flow.activity.idle() >= idle_timeout)
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        for peer in &idle {
+            if let Some(RouteFlow {
+                sender: RouteFlowSender::Mux { conn, id, .. },
+                ..
+            }) = self.flows.remove(peer)
+            {
+                if let Some(mux) = self.muxes.get(&conn) {
+                    mux.control.send(MuxControl::Close(id)).ok();
+                }
+            }
+        }
+        if !idle.is_empty() {
+            log::debug!(
+                "{} idle udp flows evicted (route: `{}` udp)",
+                idle.len(),
+                self.shared.name
+            );
+        }
+    }
+
+    /// Queue a packet for the stream of its flow, it is dropped if the stream is busy.
+    fn send(&mut self, peer: SocketAddr, packet: Bytes) {
+        let flow = match self.flows.get(&peer) {
+            Some(flow) => flow,
+            None => return,
+        };
+        flow.activity.touch();
+        let queued = match &flow.sender {
+            RouteFlowSender::Stream(packets) => packets.try_send(packet).is_ok(),
+            RouteFlowSender::Mux { conn, id, .. } => self
+                .muxes
+                .get(conn)
+                .map_or(false, |mux| mux.packets.try_send((*id, packet)).is_ok()),
+        };
+        if !queued {
+            log::debug!(
+                "udp packet from {} dropped, its stream is busy (route: `{}` udp)",
+                peer,
+                self.shared.name
+            );
+        }
+    }
+
+    /// Open a flow for a new peer, on the multiplexed stream of a client connection if the route
+    /// and the client allow it.
+    fn open_flow(&mut self, peer: SocketAddr, packet: Bytes) {
+        let mux = if self.multiplex {
+            self.shared.backends.pick().and_then(|conn| {
+                let flows = conn.flows.clone()?;
+                Some((conn, flows)).filter(|(_, flows)| flows.supports(FEATURE_UDP_MULTIPLEX))
+            })
+        } else {
+            None
+        };
+        let flow = match mux {
+            Some((conn, flows)) => self.open_mux_flow(conn, flows, peer, packet),
+            None => self.open_stream_flow(peer, packet),
+        };
+        self.flows.insert(peer, flow);
+    }
+
+    fn open_stream_flow(&mut self, peer: SocketAddr, packet: Bytes) -> RouteFlow {
+        let (packets_tx, packets) = mpsc::channel(UDP_QUEUE_SIZE);
+        let seq = self.next_seq();
+        let activity = Activity::new();
+        let shared = Arc::clone(&self.shared);
+        let flow_activity = activity.clone();
+        tokio::spawn(async move {
+            let res = stream_flow(&shared, peer, seq, flow_activity, packet, packets).await;
+            if let Err(err) = res {
+                log::warn!(
+                    "udp flow for {} failed: {:#} (route: `{}` udp)",
+                    peer,
+                    err,
+                    shared.name
+                );
+                shared.ended.send(Ended::Flow(peer, seq)).await.ok();
+            }
+        });
+
+        RouteFlow {
+            seq,
+            activity,
+            sender: RouteFlowSender::Stream(packets_tx),
+        }
+    }
+
+    fn open_mux_flow(
+        &mut self,
+        conn: PooledConn,
+        flows: Arc<Flows>,
+        peer: SocketAddr,
+        packet: Bytes,
+    ) -> RouteFlow {
+        let conn_id = conn.conn.stable_id();
+        if !self.muxes.contains_key(&conn_id) {
+            let mux = self.open_mux(conn, Arc::clone(&flows));
+            self.muxes.insert(conn_id, mux);
+        }
+
+        let id = flows.next_id();
+        let activity = Activity::new();
+        let guard = flows.insert(
+            id,
+            Arc::clone(&self.shared.socket),
+            Some(peer),
+            activity.clone(),
+        );
+        // the first packet goes over the stream, it opens the flow on the client
+        let mux = self.muxes.get(&conn_id).expect("opened above");
+        mux.control.send(MuxControl::Open(id, packet)).ok();
+
+        RouteFlow {
+            seq: self.next_seq(),
+            activity,
+            sender: RouteFlowSender::Mux {
+                conn: conn_id,
+                id,
+                _guard: guard,
+            },
+        }
+    }
+
+    fn open_mux(&mut self, conn: PooledConn, flows: Arc<Flows>) -> Mux {
+        let (packets_tx, packets) = mpsc::channel(UDP_QUEUE_SIZE);
+        let (control_tx, control) = mpsc::unbounded_channel();
+        let seq = self.next_seq();
+        let shared = Arc::clone(&self.shared);
+        tokio::spawn(async move {
+            let conn_id = conn.conn.stable_id();
+            if let Err(err) = mux_stream(&shared, conn, flows, seq, packets, control).await {
+                log::warn!(
+                    "udp multiplexed stream failed: {:#} (route: `{}` udp)",
+                    err,
+                    shared.name
+                );
+                shared.ended.send(Ended::Mux(conn_id, seq)).await.ok();
+            }
+        });
+
+        Mux {
+            seq,
+            packets: packets_tx,
+            control: control_tx,
+        }
+    }
+}
+
+/// Wait for `open` unless the route forgets the flow first, the packets received meanwhile are
+/// dropped. `None` if the flow is gone.
+async fn open_or_closed<F, T, P>(open: F, packets: &mut mpsc::Receiver<P>) -> Option<T>
+where
+    F: std::future::Future<Output = T>,
+{
+    tokio::pin!(open);
+    loop {
+        tokio::select! {
+            opened = &mut open => return Some(opened),
+            packet = packets.recv() => {
+                packet?;
+            }
+        }
+    }
+}
+
+/// Open the stream of a udp flow and write the packets the route queues for it until the route
+/// drops the flow, the stream is then finished.
+async fn stream_flow(
+    shared: &Arc<UdpShared>,
+    peer: SocketAddr,
+    seq: u64,
+    activity: Activity,
+    first: Bytes,
+    mut packets: mpsc::Receiver<Bytes>,
+) -> anyhow::Result<()> {
+    let (conn, mut send_stream, recv_stream, guard) =
+        match open_or_closed(shared.backends.open_bi(), &mut packets).await {
+            Some(opened) => opened.ok_or_else(|| anyhow::anyhow!("no client available"))?,
+            None => return Ok(()),
+        };
+    let flows = conn
+        .flows
+        .as_ref()
+        .filter(|flows| shared.datagram && flows.supports(FEATURE_UDP_DATAGRAM));
+    let id = flows.map(|flows| flows.next_id());
+    write_proto::<_, 32>(
+        &mut send_stream,
+        StreamStart {
+            route_name: shared.name.clone(),
+        },
+    )
+    .await?;
+    write_udp_stream(
+        &mut send_stream,
+        conn.flows.as_deref(),
+        UdpStream::Flow(UdpFlow { id }),
+    )
+    .await?;
+    // the first payload goes over the stream so that it can't arrive before the client knows
+    // the flow
+    send_stream.write_varint(first.len() as u32).await?;
+    send_stream.write_all(&first).await?;
+
+    let flow_guard = flows.zip(id).map(|(flows, id)| {
+        flows.insert(id, Arc::clone(&shared.socket), Some(peer), activity.clone())
+    });
+
+    let reader = Arc::clone(shared);
+    let remote_address = conn.conn.remote_address();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let _flow_guard = flow_guard;
+        let mut buf = BytesMut::new();
+        let mut buf_reader = tokio::io::BufReader::new(recv_stream);
+        loop {
+            if let anyhow::Result::<_>::Err(_) = try {
+                let packet =
+                    read_packet(&mut buf_reader, &mut buf, &reader.limit, remote_address).await?;
+                if let Some(packet) = packet {
+                    reader.socket.send_to(&packet, peer).await?;
+                    activity.touch();
+                }
+            } {
+                reader.ended.send(Ended::Flow(peer, seq)).await.ok();
+                log::info!(
+                    "udp data stream `{}` disconnect. (route: `{}` udp)",
+                    remote_address,
+                    reader.name
+                );
+                break;
+            }
+        }
+    });
+
+    let mut sender = FlowSender {
+        stream: send_stream,
+        datagram: id.map(|id| ((*conn.conn).clone(), id)),
+    };
+    while let Some(packet) = packets.recv().await {
+        sender.send(&packet).await?;
+    }
+    Ok(())
+}
+
+/// Open the multiplexed stream of a route on `conn` and write the frames of its flows until the
+/// route forgets it, the stream is then finished.
+async fn mux_stream(
+    shared: &Arc<UdpShared>,
+    conn: PooledConn,
+    flows: Arc<Flows>,
+    seq: u64,
+    mut packets: mpsc::Receiver<(u64, Bytes)>,
+    mut control: mpsc::UnboundedReceiver<MuxControl>,
+) -> anyhow::Result<()> {
+    let (mut send_stream, recv_stream, guard) =
+        match open_or_closed(conn.open_bi(), &mut packets).await {
+            Some(opened) => opened?,
+            None => return Ok(()),
+        };
+    let datagram = shared.datagram && flows.supports(FEATURE_UDP_DATAGRAM);
+    write_proto::<_, 32>(
+        &mut send_stream,
+        StreamStart {
+            route_name: shared.name.clone(),
+        },
+    )
+    .await?;
+    write_udp_stream(
+        &mut send_stream,
+        Some(&*flows),
+        UdpStream::Multiplexed { datagram },
+    )
+    .await?;
+
+    let conn_id = conn.conn.stable_id();
+    let remote_address = conn.conn.remote_address();
+    let reader = Arc::clone(shared);
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut buf = BytesMut::new();
+        let mut buf_reader = tokio::io::BufReader::new(recv_stream);
+        loop {
+            match read_mux_frame(&mut buf_reader, &mut buf, &reader.limit, remote_address).await {
+                Ok(MuxFrame::Packet(id, Some(packet))) => flows.forward(id, &packet),
+                // flows are only closed by the server
+                Ok(_) => {}
+                Err(_) => {
+                    reader.ended.send(Ended::Mux(conn_id, seq)).await.ok();
+                    log::info!(
+                        "udp multiplexed stream `{}` disconnect. (route: `{}` udp)",
+                        remote_address,
+                        reader.name
+                    );
+                    break;
+                }
+            }
+        }
     });
 
+    let datagram = datagram.then(|| (*conn.conn).clone());
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(msg) = control.recv() => match msg {
+                MuxControl::Open(id, packet) => {
+                    write_mux_packet(&mut send_stream, id, &packet).await?
+                }
+                MuxControl::Close(id) => write_mux_close(&mut send_stream, id).await?,
+            },
+            packet = packets.recv() => match packet {
+                Some((id, packet)) => {
+                    let sent = datagram
+                        .as_ref()
+                        .map_or(false, |conn| udp::send_datagram(conn, id, &packet));
+                    if !sent {
+                        write_mux_packet(&mut send_stream, id, &packet).await?;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
     Ok(())
 }
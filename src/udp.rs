@@ -0,0 +1,437 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHashMap;
+use futures_util::StreamExt;
+use integer_encoding::{VarInt, VarIntAsyncReader};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use quinn::{Connection, Datagrams, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+use crate::config::OversizePolicy;
+use crate::proto::{
+    read_proto, write_proto, UdpFlow, UdpStream, VarIntWriter, FEATURE_UDP_DATAGRAM,
+    FEATURE_UDP_MULTIPLEX,
+};
+
+const DEFAULT_UDP_BUFFER: usize = 2048;
+/// The largest payload of a udp packet.
+const MAX_UDP_PAYLOAD: usize = 65535;
+
+/// The udp flows of a connection whose payloads may be carried by QUIC datagrams or by a
+/// multiplexed stream, by flow id.
+///
+/// A datagram is the varint flow id followed by the payload.
+pub struct Flows {
+    /// The features supported by both sides of the connection.
+    features: u64,
+    next: AtomicU64,
+    flows: Mutex<FnvHashMap<u64, Flow>>,
+}
+
+struct Flow {
+    socket: Arc<UdpSocket>,
+    /// `None` if the socket is connected.
+    peer: Option<SocketAddr>,
+    activity: Activity,
+}
+
+/// When a flow last carried a packet, in either direction.
+#[derive(Clone)]
+pub struct Activity(Arc<AtomicU64>);
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+impl Activity {
+    pub fn new() -> Self {
+        let activity = Activity(Arc::new(AtomicU64::new(0)));
+        activity.touch();
+        activity
+    }
+
+    pub fn touch(&self) {
+        self.0
+            .store(START.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) -> Duration {
+        START
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity::new()
+    }
+}
+
+/// Unregisters its flow when dropped.
+pub struct FlowGuard {
+    flows: Arc<Flows>,
+    id: u64,
+}
+
+impl Flows {
+    pub fn supports(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// A new flow id, unique on this connection.
+    pub fn next_id(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Forward the datagrams of flow `id` to `peer` through `socket`, or to the address the socket
+    /// is connected to.
+    pub fn insert(
+        self: &Arc<Self>,
+        id: u64,
+        socket: Arc<UdpSocket>,
+        peer: Option<SocketAddr>,
+        activity: Activity,
+    ) -> FlowGuard {
+        self.flows.lock().insert(
+            id,
+            Flow {
+                socket,
+                peer,
+                activity,
+            },
+        );
+        FlowGuard {
+            flows: Arc::clone(self),
+            id,
+        }
+    }
+
+    /// Send a packet of flow `id` to its peer, dropping it if the flow is unknown or the socket
+    /// is busy.
+    pub fn forward(&self, id: u64, packet: &[u8]) {
+        let flow = self.flows.lock().get(&id).map(|f| {
+            f.activity.touch();
+            (Arc::clone(&f.socket), f.peer)
+        });
+        let res = match flow {
+            Some((socket, Some(peer))) => socket.try_send_to(packet, peer),
+            Some((socket, None)) => socket.try_send(packet),
+            None => return log::debug!("packet of unknown udp flow {}", id),
+        };
+        if let Err(err) = res {
+            log::debug!("failed to forward packet of udp flow {}: {}", id, err);
+        }
+    }
+
+    fn dispatch(&self, datagram: Bytes) {
+        match u64::decode_var(&datagram) {
+            Some((id, len)) => self.forward(id, &datagram[len..]),
+            None => log::debug!("malformed datagram"),
+        }
+    }
+}
+
+impl Drop for FlowGuard {
+    fn drop(&mut self) {
+        self.flows.flows.lock().remove(&self.id);
+    }
+}
+
+/// Dispatch the datagrams of a connection to its flows until it is closed, if both sides
+/// support `FEATURE_UDP_DATAGRAM` or `FEATURE_UDP_MULTIPLEX`.
+pub fn spawn_flows(features: u64, mut datagrams: Datagrams) -> Option<Arc<Flows>> {
+    if features & (FEATURE_UDP_DATAGRAM | FEATURE_UDP_MULTIPLEX) == 0 {
+        return None;
+    }
+    let flows = Arc::new(Flows {
+        features,
+        next: AtomicU64::new(0),
+        flows: Mutex::new(FnvHashMap::default()),
+    });
+    let dispatcher = Arc::clone(&flows);
+    tokio::spawn(async move {
+        while let Some(Ok(datagram)) = datagrams.next().await {
+            dispatcher.dispatch(datagram);
+        }
+    });
+    Some(flows)
+}
+
+/// Sends the payloads of a udp flow as QUIC datagrams if the flow has an id, over its stream
+/// with a varint length otherwise. Payloads the peer or the path can't take as a datagram also
+/// go over the stream.
+pub struct FlowSender {
+    pub stream: SendStream,
+    pub datagram: Option<(Connection, u64)>,
+}
+
+impl FlowSender {
+    pub async fn send(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        if let Some((conn, id)) = &self.datagram {
+            if send_datagram(conn, *id, payload) {
+                return Ok(());
+            }
+        }
+        self.stream.write_varint(payload.len() as u32).await?;
+        self.stream.write_all(payload).await?;
+        Ok(())
+    }
+}
+
+/// Send a payload of flow `id` as a QUIC datagram, false if it doesn't fit in one or the peer
+/// doesn't accept datagrams.
+pub fn send_datagram(conn: &Connection, id: u64, payload: &[u8]) -> bool {
+    let mut datagram = BytesMut::zeroed(id.required_space());
+    id.encode_var(&mut datagram);
+    datagram.extend_from_slice(payload);
+    conn.max_datagram_size()
+        .map_or(false, |max| datagram.len() <= max)
+        && conn.send_datagram(datagram.freeze()).is_ok()
+}
+
+/// Write the header of a udp stream after its `StreamStart`, according to the features of the
+/// connection.
+pub async fn write_udp_stream(
+    stream: &mut SendStream,
+    flows: Option<&Flows>,
+    udp_stream: UdpStream,
+) -> anyhow::Result<()> {
+    match flows {
+        Some(flows) if flows.supports(FEATURE_UDP_MULTIPLEX) => {
+            write_proto::<_, 16>(stream, udp_stream).await
+        }
+        Some(flows) if flows.supports(FEATURE_UDP_DATAGRAM) => match udp_stream {
+            UdpStream::Flow(flow) => write_proto::<_, 16>(stream, flow).await,
+            UdpStream::Multiplexed { .. } => anyhow::bail!("multiplexing is not supported"),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Read the header of a udp stream written by `write_udp_stream`.
+pub async fn read_udp_stream(
+    stream: &mut RecvStream,
+    flows: Option<&Flows>,
+) -> anyhow::Result<UdpStream> {
+    Ok(match flows {
+        Some(flows) if flows.supports(FEATURE_UDP_MULTIPLEX) => {
+            read_proto::<UdpStream, 16>(stream).await?
+        }
+        Some(flows) if flows.supports(FEATURE_UDP_DATAGRAM) => {
+            UdpStream::Flow(read_proto::<UdpFlow, 16>(stream).await?)
+        }
+        _ => UdpStream::Flow(UdpFlow { id: None }),
+    })
+}
+
+/// A frame of a multiplexed udp stream: the varint `id << 1` followed by a packet framed like on
+/// a stream of its own, or `id << 1 | 1` alone when the flow is closed.
+pub enum MuxFrame {
+    /// `None` if the packet was dropped by the size limit.
+    Packet(u64, Option<Bytes>),
+    Close(u64),
+}
+
+pub async fn write_mux_packet<W: AsyncWrite + Send + Unpin>(
+    stream: &mut W,
+    id: u64,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    stream.write_varint(id << 1).await?;
+    stream.write_varint(payload.len() as u32).await?;
+    stream.write_all(payload).await?;
+    Ok(())
+}
+
+pub async fn write_mux_close<W: AsyncWrite + Send + Unpin>(
+    stream: &mut W,
+    id: u64,
+) -> anyhow::Result<()> {
+    stream.write_varint(id << 1 | 1).await?;
+    Ok(())
+}
+
+pub async fn read_mux_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    limit: &SizeLimit,
+    from: impl Display,
+) -> anyhow::Result<MuxFrame> {
+    let header: u64 = reader.read_varint_async().await?;
+    let id = header >> 1;
+    if header & 1 == 1 {
+        return Ok(MuxFrame::Close(id));
+    }
+    Ok(MuxFrame::Packet(
+        id,
+        read_packet(reader, buf, limit, from).await?,
+    ))
+}
+
+/// The largest packet of a udp route and what happens to larger ones.
+pub struct SizeLimit {
+    max: usize,
+    policy: OversizePolicy,
+    route: String,
+    oversized: AtomicU64,
+}
+
+impl SizeLimit {
+    pub fn new(max: Option<usize>, policy: OversizePolicy, route: String) -> Self {
+        SizeLimit {
+            max: max.unwrap_or(DEFAULT_UDP_BUFFER).min(MAX_UDP_PAYLOAD),
+            policy,
+            route,
+            oversized: AtomicU64::new(0),
+        }
+    }
+
+    /// The size of the buffer to receive packets in, one byte more than the limit so that a
+    /// truncated packet can be told apart from one that fits.
+    pub fn recv_buffer_size(&self) -> usize {
+        match self.policy {
+            OversizePolicy::Fragment => MAX_UDP_PAYLOAD,
+            _ => self.max + 1,
+        }
+    }
+
+    /// Whether a packet of `len` bytes from `from` is forwarded, counting the oversized ones.
+    pub fn check(&self, len: usize, from: impl Display) -> bool {
+        if len <= self.max {
+            return true;
+        }
+        let count = self.oversized.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.policy {
+            OversizePolicy::Drop => {
+                log::debug!(
+                    "udp packet from {} larger than {} bytes dropped, {} so far (route: `{}` udp)",
+                    from,
+                    self.max,
+                    count,
+                    self.route
+                );
+                false
+            }
+            OversizePolicy::Log => {
+                log::warn!(
+                    "udp packet from {} larger than {} bytes dropped, {} so far (route: `{}` udp)",
+                    from,
+                    self.max,
+                    count,
+                    self.route
+                );
+                false
+            }
+            OversizePolicy::Fragment => true,
+        }
+    }
+}
+
+/// Read a packet framed with its varint length from a udp stream, `None` if it is dropped by
+/// `limit`. A length no udp packet can have is an error.
+pub async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    limit: &SizeLimit,
+    from: impl Display,
+) -> anyhow::Result<Option<Bytes>> {
+    let len: usize = reader.read_varint_async().await?;
+    anyhow::ensure!(len <= MAX_UDP_PAYLOAD, "invalid udp packet length {}", len);
+    if !limit.check(len, from) {
+        tokio::io::copy(&mut (&mut *reader).take(len as u64), &mut tokio::io::sink()).await?;
+        return Ok(None);
+    }
+    buf.resize(len, 0);
+    reader.read_exact(buf).await?;
+    Ok(Some(buf.split().freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut frame = payload.len().encode_var_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn packet_length_above_max() {
+        let limit = SizeLimit::new(None, OversizePolicy::Drop, String::new());
+        let len = (MAX_UDP_PAYLOAD + 1).encode_var_vec();
+        let res = read_packet(&mut &len[..], &mut BytesMut::new(), &limit, "test").await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_packet() {
+        for policy in [OversizePolicy::Drop, OversizePolicy::Log] {
+            let limit = SizeLimit::new(Some(4), policy, String::new());
+            let mut data = framed(b"12345");
+            data.extend(framed(b"1234"));
+            let mut reader = &data[..];
+            let mut buf = BytesMut::new();
+            let packet = read_packet(&mut reader, &mut buf, &limit, "test").await;
+            assert_eq!(packet.unwrap(), None);
+            // the dropped packet is skipped, the next one is read in full
+            let packet = read_packet(&mut reader, &mut buf, &limit, "test").await;
+            assert_eq!(packet.unwrap().as_deref(), Some(&b"1234"[..]));
+            assert_eq!(limit.oversized.load(Ordering::Relaxed), 1);
+        }
+
+        let limit = SizeLimit::new(Some(4), OversizePolicy::Fragment, String::new());
+        let data = framed(b"12345");
+        let packet = read_packet(&mut &data[..], &mut BytesMut::new(), &limit, "test").await;
+        assert_eq!(packet.unwrap().as_deref(), Some(&b"12345"[..]));
+        assert_eq!(limit.oversized.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn mux_frames() {
+        let mut data = Vec::new();
+        write_mux_packet(&mut data, 7, b"packet").await.unwrap();
+        write_mux_close(&mut data, 7).await.unwrap();
+        write_mux_packet(&mut data, 0, b"").await.unwrap();
+
+        let limit = SizeLimit::new(None, OversizePolicy::Drop, String::new());
+        let mut reader = &data[..];
+        let mut buf = BytesMut::new();
+        match read_mux_frame(&mut reader, &mut buf, &limit, "test")
+            .await
+            .unwrap()
+        {
+            MuxFrame::Packet(7, Some(packet)) => assert_eq!(&packet[..], b"packet"),
+            _ => panic!("expected the packet of flow 7"),
+        }
+        match read_mux_frame(&mut reader, &mut buf, &limit, "test")
+            .await
+            .unwrap()
+        {
+            MuxFrame::Close(7) => {}
+            _ => panic!("expected the close of flow 7"),
+        }
+        match read_mux_frame(&mut reader, &mut buf, &limit, "test")
+            .await
+            .unwrap()
+        {
+            MuxFrame::Packet(0, Some(packet)) => assert!(packet.is_empty()),
+            _ => panic!("expected the empty packet of flow 0"),
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn recv_buffer_size() {
+        let size = |max, policy| SizeLimit::new(max, policy, String::new()).recv_buffer_size();
+        assert_eq!(size(Some(1500), OversizePolicy::Drop), 1501);
+        assert_eq!(size(None, OversizePolicy::Log), DEFAULT_UDP_BUFFER + 1);
+        assert_eq!(size(Some(1500), OversizePolicy::Fragment), MAX_UDP_PAYLOAD);
+    }
+}